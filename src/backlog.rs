@@ -17,67 +17,110 @@ impl<K, V> Backlog<K, V> {
             table_max,
         }
     }
-    pub fn clean(&self, timeout: Duration) {
+    /// Removes and returns the incomplete entries that timed out by `now`.
+    pub fn clean(&self, now: Instant, timeout: Duration) -> Vec<(K, Vec<V>)> {
         self.incomplete_table
             .write()
             .unwrap()
-            .retain(|_, v: &mut EphemeralVec<V>| !v.timed_out(timeout));
+            .extract_if(|_, v| v.timed_out(now, timeout))
+            .map(|(k, v)| (k, v.list))
+            .collect()
+    }
+    /// Removes and returns the entries `eligible` picks out of those that got their first value at least `grace` before `now` and have at least `min` values by now.
+    pub fn take_partial(
+        &self,
+        now: Instant,
+        grace: Duration,
+        min: NonZeroUsize,
+        eligible: impl Fn(&[V]) -> bool,
+    ) -> Vec<(K, Vec<V>)> {
+        self.incomplete_table
+            .write()
+            .unwrap()
+            .extract_if(|_, v| {
+                now.saturating_duration_since(v.created) >= grace
+                    && v.list.len() >= min.get()
+                    && eligible(&v.list)
+            })
+            .map(|(k, v)| (k, v.list))
+            .collect()
+    }
+    /// Number of keys still waiting for values.
+    pub fn len(&self) -> usize {
+        self.incomplete_table.read().unwrap().len()
     }
 }
 impl<K, V> Backlog<K, V>
 where
     K: Clone + Eq + core::hash::Hash,
 {
-    pub fn handle(&self, key: K, value: V, size: NonZeroUsize) -> Option<Vec<V>> {
+    /// Whether [`Self::handle`] would take a value under `key` now.
+    pub fn admits(&self, key: &K) -> bool {
+        let incomplete_table = self.incomplete_table.read().unwrap();
+        incomplete_table.contains_key(key) || incomplete_table.len() < self.table_max.get()
+    }
+    /// Returns the complete list once `size` values have arrived under `key`; `now` is when `value` arrived.
+    pub fn handle(
+        &self,
+        key: K,
+        value: V,
+        size: NonZeroUsize,
+        now: Instant,
+    ) -> Result<Option<Vec<V>>, BacklogFull> {
         let mut incomplete_table = self.incomplete_table.write().unwrap();
         match incomplete_table.remove(&key) {
             Some(incomplete_list) => {
-                let res = incomplete_list.push(value);
+                let res = incomplete_list.push(value, now);
                 match res {
                     PushResult::Incomplete(incomplete_list) => {
                         incomplete_table.insert(key, incomplete_list);
-                        None
+                        Ok(None)
                     }
-                    PushResult::Complete(list) => Some(list),
+                    PushResult::Complete(list) => Ok(Some(list)),
                 }
             }
             None => {
                 if self.table_max.get() <= incomplete_table.len() {
-                    return None;
+                    return Err(BacklogFull);
                 }
-                let incomplete_list = EphemeralVec::new(size);
+                let incomplete_list = EphemeralVec::new(size, now);
                 incomplete_table.insert(key.clone(), incomplete_list);
                 drop(incomplete_table);
-                self.handle(key, value, size)
+                self.handle(key, value, size, now)
             }
         }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct BacklogFull;
+
 #[derive(Debug)]
 struct EphemeralVec<T> {
     list: Vec<T>,
     size: NonZeroUsize,
+    created: Instant,
     last_update: Instant,
 }
 impl<T> EphemeralVec<T> {
-    pub fn new(size: NonZeroUsize) -> Self {
+    pub fn new(size: NonZeroUsize, now: Instant) -> Self {
         Self {
             list: vec![],
             size,
-            last_update: Instant::now(),
+            created: now,
+            last_update: now,
         }
     }
-    pub fn push(mut self, value: T) -> PushResult<T> {
+    pub fn push(mut self, value: T, now: Instant) -> PushResult<T> {
         self.list.push(value);
         if self.list.len() == self.size.get() {
             return PushResult::Complete(self.list);
         }
-        self.last_update = Instant::now();
+        self.last_update = now;
         PushResult::Incomplete(self)
     }
-    pub fn timed_out(&self, timeout: Duration) -> bool {
-        self.last_update.elapsed() > timeout
+    pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        now.saturating_duration_since(self.last_update) > timeout
     }
 }
 #[derive(Debug)]
@@ -85,3 +128,35 @@ enum PushResult<T> {
     Incomplete(EphemeralVec<T>),
     Complete(Vec<T>),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn age_by_the_given_clock() {
+        let backlog = Backlog::new(NonZeroUsize::new(2).unwrap());
+        let three = NonZeroUsize::new(3).unwrap();
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        assert!(matches!(backlog.handle("a", 0, three, start), Ok(None)));
+        assert!(matches!(backlog.handle("b", 0, three, start), Ok(None)));
+        assert!(backlog.handle("c", 0, three, start).is_err());
+        assert!(backlog.admits(&"a"));
+        assert!(!backlog.admits(&"c"));
+        assert!(matches!(
+            backlog.handle("a", 1, three, start + second),
+            Ok(None)
+        ));
+        // Only `b` has been quiet for longer than the timeout
+        let timed_out = backlog.clean(start + second * 2, second);
+        assert_eq!(timed_out, [("b", vec![0])]);
+        // The grace period runs from the first value of `a`
+        let min = NonZeroUsize::new(2).unwrap();
+        let taken = backlog.take_partial(start + second, second * 2, min, |_| true);
+        assert!(taken.is_empty());
+        let taken = backlog.take_partial(start + second * 2, second * 2, min, |_| true);
+        assert_eq!(taken, [("a", vec![0, 1])]);
+        assert_eq!(backlog.len(), 0);
+    }
+}
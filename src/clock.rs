@@ -0,0 +1,57 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+/// Source of the time path stats and schedulers are fed with.
+pub trait Clock: core::fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    /// Completes once [`Self::now`] has reached `deadline`.
+    ///
+    /// The default waits in real time, which suits clocks that advance with it.
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let left = deadline.saturating_duration_since(self.now());
+        Box::pin(tokio::time::sleep(left))
+    }
+}
+
+/// Reads the time from the OS.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Stands still until advanced, for replaying a session step by step.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: watch::Sender<Instant>,
+}
+impl ManualClock {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: watch::Sender::new(start),
+        }
+    }
+    /// Also wakes whatever sleeps until the new time.
+    pub fn advance(&self, by: Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // The sender lives as long as `self`
+            let _ = now.wait_for(|now| deadline <= *now).await;
+        })
+    }
+}
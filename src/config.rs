@@ -0,0 +1,420 @@
+use std::{io, num::NonZeroUsize, sync::Arc, time::Duration};
+
+#[cfg(feature = "prometheus")]
+use crate::metrics::Registry;
+use crate::{
+    clock::{Clock, SystemClock},
+    qlog::EventSink,
+    read::Delivery,
+    schedule::NewScheduler,
+};
+
+/// Settings of [`crate::conn::MpUdpConn::connect`].
+#[derive(Debug, Clone)]
+pub struct MpUdpConfig {
+    handshake_timeout: Duration,
+    init_retransmit_interval: Duration,
+    packet_buffer_len: NonZeroUsize,
+    session: SessionConfig,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Registry>,
+}
+impl MpUdpConfig {
+    pub fn builder() -> MpUdpConfigBuilder {
+        MpUdpConfigBuilder {
+            config: Self::default(),
+        }
+    }
+    /// How long `connect` waits for the listener to acknowledge every path.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+    /// How long `connect` waits for an acknowledgement before resending `Init` on a path.
+    pub fn init_retransmit_interval(&self) -> Duration {
+        self.init_retransmit_interval
+    }
+    /// Capacity of the buffers datagrams are received into.
+    pub fn packet_buffer_len(&self) -> NonZeroUsize {
+        self.packet_buffer_len
+    }
+    pub fn session(&self) -> &SessionConfig {
+        &self.session
+    }
+    /// Where the connection reports its paths, if anywhere.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(&self) -> Option<&Registry> {
+        self.metrics.as_ref()
+    }
+}
+impl Default for MpUdpConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(10),
+            init_retransmit_interval: Duration::from_millis(250),
+            packet_buffer_len: NonZeroUsize::new(2_usize.pow(16)).unwrap(),
+            session: SessionConfig::default(),
+            #[cfg(feature = "prometheus")]
+            metrics: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MpUdpConfigBuilder {
+    config: MpUdpConfig,
+}
+impl MpUdpConfigBuilder {
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = timeout;
+        self
+    }
+    pub fn init_retransmit_interval(mut self, interval: Duration) -> Self {
+        self.config.init_retransmit_interval = interval;
+        self
+    }
+    pub fn packet_buffer_len(mut self, len: NonZeroUsize) -> Self {
+        self.config.packet_buffer_len = len;
+        self
+    }
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(mut self, registry: Registry) -> Self {
+        self.config.metrics = Some(registry);
+        self
+    }
+    /// Settings of the session once it is established, see [`SessionConfig::builder`].
+    pub fn session(mut self, session: SessionConfig) -> Self {
+        self.config.session = session;
+        self
+    }
+    pub fn build(self) -> io::Result<MpUdpConfig> {
+        let config = self.config;
+        if config.handshake_timeout.is_zero() {
+            return Err(invalid("handshake timeout cannot be zero"));
+        }
+        if config.init_retransmit_interval.is_zero() {
+            return Err(invalid("init retransmit interval cannot be zero"));
+        }
+        Ok(config)
+    }
+}
+
+/// Settings of [`crate::listen::MpUdpListener::bind`].
+#[derive(Debug, Clone)]
+pub struct MpUdpListenerConfig {
+    max_session_conns: NonZeroUsize,
+    dispatcher_buffer_size: NonZeroUsize,
+    backlog_timeout: Duration,
+    backlog_max: NonZeroUsize,
+    partial_accept: Option<PartialAccept>,
+    session: SessionConfig,
+    #[cfg(feature = "prometheus")]
+    metrics: Option<Registry>,
+}
+impl MpUdpListenerConfig {
+    pub fn builder() -> MpUdpListenerConfigBuilder {
+        MpUdpListenerConfigBuilder {
+            config: Self::default(),
+        }
+    }
+    /// Most paths a client may open in one session.
+    pub fn max_session_conns(&self) -> NonZeroUsize {
+        self.max_session_conns
+    }
+    /// Datagrams queued per peer address before the listener drops them.
+    pub fn dispatcher_buffer_size(&self) -> NonZeroUsize {
+        self.dispatcher_buffer_size
+    }
+    /// How long an incomplete handshake is kept after its last path arrived.
+    pub fn backlog_timeout(&self) -> Duration {
+        self.backlog_timeout
+    }
+    /// Sessions still waiting for some of their paths, and separately completed sessions queued until they are accepted.
+    ///
+    /// Handshakes of new sessions beyond it are rejected.
+    pub fn backlog_max(&self) -> NonZeroUsize {
+        self.backlog_max
+    }
+    /// When a session missing some of its paths is accepted anyway, if ever.
+    pub fn partial_accept(&self) -> Option<PartialAccept> {
+        self.partial_accept
+    }
+    pub fn session(&self) -> &SessionConfig {
+        &self.session
+    }
+    /// Where the listener reports its handshakes and accepted sessions, if anywhere.
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(&self) -> Option<&Registry> {
+        self.metrics.as_ref()
+    }
+}
+impl Default for MpUdpListenerConfig {
+    fn default() -> Self {
+        Self {
+            max_session_conns: NonZeroUsize::new(16).unwrap(),
+            dispatcher_buffer_size: NonZeroUsize::new(64).unwrap(),
+            backlog_timeout: Duration::from_secs(60),
+            backlog_max: NonZeroUsize::new(64).unwrap(),
+            partial_accept: None,
+            session: SessionConfig::default(),
+            #[cfg(feature = "prometheus")]
+            metrics: None,
+        }
+    }
+}
+
+/// Accepting sessions whose other paths are blocked on the way, see [`MpUdpListenerConfig::partial_accept`].
+///
+/// Paths that open after the session was accepted join it, provided the client supports [`crate::Features::PARTIAL`].
+#[derive(Debug, Clone, Copy)]
+pub struct PartialAccept {
+    /// How long after its first path a session waits for the rest.
+    pub grace_period: Duration,
+    /// Fewest paths a session is accepted with.
+    pub min_paths: NonZeroUsize,
+}
+
+#[derive(Debug, Clone)]
+pub struct MpUdpListenerConfigBuilder {
+    config: MpUdpListenerConfig,
+}
+impl MpUdpListenerConfigBuilder {
+    pub fn max_session_conns(mut self, max: NonZeroUsize) -> Self {
+        self.config.max_session_conns = max;
+        self
+    }
+    pub fn dispatcher_buffer_size(mut self, size: NonZeroUsize) -> Self {
+        self.config.dispatcher_buffer_size = size;
+        self
+    }
+    pub fn backlog_timeout(mut self, timeout: Duration) -> Self {
+        self.config.backlog_timeout = timeout;
+        self
+    }
+    pub fn backlog_max(mut self, max: NonZeroUsize) -> Self {
+        self.config.backlog_max = max;
+        self
+    }
+    pub fn partial_accept(mut self, partial_accept: Option<PartialAccept>) -> Self {
+        self.config.partial_accept = partial_accept;
+        self
+    }
+    #[cfg(feature = "prometheus")]
+    pub fn metrics(mut self, registry: Registry) -> Self {
+        self.config.metrics = Some(registry);
+        self
+    }
+    /// Settings of each session once it is accepted, see [`SessionConfig::builder`].
+    pub fn session(mut self, session: SessionConfig) -> Self {
+        self.config.session = session;
+        self
+    }
+    pub fn build(self) -> io::Result<MpUdpListenerConfig> {
+        let config = self.config;
+        if config.backlog_timeout.is_zero() {
+            return Err(invalid("backlog timeout cannot be zero"));
+        }
+        if config
+            .partial_accept
+            .is_some_and(|partial| partial.grace_period.is_zero())
+        {
+            return Err(invalid("partial accept grace period cannot be zero"));
+        }
+        Ok(config)
+    }
+}
+
+/// Settings that apply to an established session on either end.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    delivery: Delivery,
+    new_scheduler: NewScheduler,
+    read_queue: NonZeroUsize,
+    liveness: Liveness,
+    clock: Arc<dyn Clock>,
+    latency_histogram: bool,
+    event_sink: Option<EventSink>,
+    rtt_change_threshold: f64,
+    keepalive_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    close_drain_timeout: Duration,
+}
+impl SessionConfig {
+    pub fn builder() -> SessionConfigBuilder {
+        SessionConfigBuilder {
+            config: Self::default(),
+        }
+    }
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+    pub fn new_scheduler(&self) -> &NewScheduler {
+        &self.new_scheduler
+    }
+    /// Datagrams buffered between the path receivers and [`crate::read::MpUdpRead`].
+    pub fn read_queue(&self) -> NonZeroUsize {
+        self.read_queue
+    }
+    pub fn liveness(&self) -> Liveness {
+        self.liveness
+    }
+    /// Time source of the path stats the scheduler works from.
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+    /// Whether RTT samples are collected into [`crate::stats::SessionStats::latency`].
+    pub fn latency_histogram(&self) -> bool {
+        self.latency_histogram
+    }
+    /// Where the protocol events of each session are logged, if anywhere.
+    pub fn event_sink(&self) -> Option<&EventSink> {
+        self.event_sink.as_ref()
+    }
+    /// Share of the last reported smoothed RTT a path has to drift by before [`crate::event::PathEvent::RttChanged`] is sent again.
+    pub fn rtt_change_threshold(&self) -> f64 {
+        self.rtt_change_threshold
+    }
+    /// How often a keepalive frame goes out on a path nothing else was sent on, if at all; off unless set.
+    ///
+    /// Should stay below the time NATs on the way keep an idle UDP mapping, e.g. 15 seconds for the common 30.
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval
+    }
+    /// How long no path may receive anything before [`crate::read::MpUdpRead::recv`] gives up on the session, if ever.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+    /// How long a closed session keeps receiving the datagrams the peer sent before closing, in case some are still on the way.
+    pub fn close_drain_timeout(&self) -> Duration {
+        self.close_drain_timeout
+    }
+    fn validate(&self) -> io::Result<()> {
+        if let Delivery::Ordered { gap_timeout, .. } = self.delivery
+            && gap_timeout.is_zero()
+        {
+            return Err(invalid("gap timeout cannot be zero"));
+        }
+        if !self.rtt_change_threshold.is_finite() || self.rtt_change_threshold < 0. {
+            return Err(invalid(
+                "RTT change threshold must be finite and non-negative",
+            ));
+        }
+        if self
+            .keepalive_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(invalid("keepalive interval cannot be zero"));
+        }
+        if self.idle_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(invalid("idle timeout cannot be zero"));
+        }
+        let liveness = &self.liveness;
+        if liveness.probe_interval.is_zero() {
+            return Err(invalid("probe interval cannot be zero"));
+        }
+        if liveness.suspect_after < liveness.probe_interval {
+            return Err(invalid("paths must be probed before they become suspect"));
+        }
+        if liveness.dead_after < liveness.suspect_after {
+            return Err(invalid("paths must become suspect before they die"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            delivery: Delivery::default(),
+            new_scheduler: NewScheduler::default(),
+            read_queue: NonZeroUsize::new(1).unwrap(),
+            liveness: Liveness::default(),
+            clock: Arc::new(SystemClock),
+            latency_histogram: false,
+            event_sink: None,
+            rtt_change_threshold: 0.25,
+            keepalive_interval: None,
+            idle_timeout: None,
+            close_drain_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfigBuilder {
+    config: SessionConfig,
+}
+impl SessionConfigBuilder {
+    pub fn delivery(mut self, delivery: Delivery) -> Self {
+        self.config.delivery = delivery;
+        self
+    }
+    pub fn scheduler(mut self, new_scheduler: NewScheduler) -> Self {
+        self.config.new_scheduler = new_scheduler;
+        self
+    }
+    pub fn read_queue(mut self, len: NonZeroUsize) -> Self {
+        self.config.read_queue = len;
+        self
+    }
+    pub fn liveness(mut self, liveness: Liveness) -> Self {
+        self.config.liveness = liveness;
+        self
+    }
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.config.clock = clock;
+        self
+    }
+    pub fn latency_histogram(mut self, enabled: bool) -> Self {
+        self.config.latency_histogram = enabled;
+        self
+    }
+    pub fn event_sink(mut self, sink: EventSink) -> Self {
+        self.config.event_sink = Some(sink);
+        self
+    }
+    pub fn rtt_change_threshold(mut self, threshold: f64) -> Self {
+        self.config.rtt_change_threshold = threshold;
+        self
+    }
+    pub fn keepalive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.keepalive_interval = interval;
+        self
+    }
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+    pub fn close_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.close_drain_timeout = timeout;
+        self
+    }
+    pub fn build(self) -> io::Result<SessionConfig> {
+        let config = self.config;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// When silent paths are probed and given up on.
+///
+/// Only in effect if the peer answers pings.
+#[derive(Debug, Clone, Copy)]
+pub struct Liveness {
+    /// Paths that received nothing for this long are probed, at this interval.
+    pub probe_interval: Duration,
+    pub suspect_after: Duration,
+    pub dead_after: Duration,
+}
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(1),
+            suspect_after: Duration::from_secs(3),
+            dead_after: Duration::from_secs(10),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
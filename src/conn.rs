@@ -3,14 +3,26 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     num::NonZeroUsize,
     sync::Arc,
+    time::Duration,
 };
 
-use tokio::net::UdpSocket;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, watch},
+    task::JoinSet,
+};
 
 use crate::{
-    message::{Header, Init, Session},
-    read::{MpUdpRead, UdpRecver},
-    schedule::new_stats,
+    config::{MpUdpConfig, SessionConfig},
+    event::PathEvent,
+    message::{
+        Features, Header, INIT_ACK_SIZE, Init, InitAck, Kind, Secret, Session, Side,
+        decode_listener_frame, decode_path_ids,
+    },
+    qlog::EventLog,
+    read::{MpUdpRead, PathJoiner, UdpRecver},
+    schedule::{PathRole, PathState, new_stats},
+    stats::{SessionStats, StatsSource},
     write::{MpUdpWrite, UdpSender},
 };
 
@@ -18,10 +30,120 @@ use crate::{
 pub struct MpUdpConn {
     write: MpUdpWrite,
     read: MpUdpRead,
+    features: Features,
+    config: SessionConfig,
+    /// Only set on the end that connected.
+    client: Option<Client>,
+}
+/// What the connecting end needs to open more paths.
+#[derive(Debug)]
+struct Client {
+    init: Init,
+    secret: Secret,
+    config: MpUdpConfig,
+    /// Id of the next path opened; ids are never reused.
+    next_path: u32,
+    /// Paths the listener accepted the session without, still trying to join.
+    _late_paths: JoinSet<()>,
 }
 impl MpUdpConn {
-    pub(crate) fn new(read: MpUdpRead, write: MpUdpWrite) -> Self {
-        Self { write, read }
+    pub(crate) fn new(
+        read: MpUdpRead,
+        write: MpUdpWrite,
+        features: Features,
+        config: SessionConfig,
+    ) -> Self {
+        Self {
+            write,
+            read,
+            features,
+            config,
+            client: None,
+        }
+    }
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+    /// Settings the session was opened with by [`Self::connect`]; `None` on the listening end, whose settings are in [`crate::listen::MpUdpListener::config`].
+    pub fn connect_config(&self) -> Option<&MpUdpConfig> {
+        self.client.as_ref().map(|client| &client.config)
+    }
+    /// Protocol capabilities this session runs with.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+    /// Probes every path and returns the RTTs of those that answered within `timeout`, indexed by path.
+    pub async fn ping(&mut self, timeout: Duration) -> io::Result<Vec<Option<Duration>>> {
+        if !self.features.contains(Features::PING) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "peer does not answer pings",
+            ));
+        }
+        Ok(self.write.ping(self.read.pong(), timeout).await)
+    }
+    /// Opens another path to `addr` and waits until the listener has acknowledged it, returning the index of the path.
+    ///
+    /// Only the end that connected can add paths.
+    pub async fn add_path(&mut self, addr: SocketAddr, role: PathRole) -> io::Result<usize> {
+        self.check_path_changes()?;
+        let Some(client) = &mut self.client else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only the connecting end can add paths",
+            ));
+        };
+        let path = client.next_path;
+        client.next_path = path
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many paths"))?;
+        let socket = Arc::new(connected_socket(addr).await?);
+        join_path(
+            self.read.joiner(),
+            socket,
+            client.init,
+            client.secret,
+            path,
+            role,
+            &client.config,
+        )
+        .await
+    }
+    /// Takes `path` out of the session on both ends; the index is not reused.
+    pub async fn remove_path(&mut self, path: usize) -> io::Result<()> {
+        self.check_path_changes()?;
+        let change = self.write.retire_path(path).await?;
+        self.read.path_changed(path, change);
+        Ok(())
+    }
+    fn check_path_changes(&self) -> io::Result<()> {
+        if !self.features.contains(Features::PATH_CHANGES) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "peer cannot change paths",
+            ));
+        }
+        Ok(())
+    }
+    /// Tells the peer the session is over and releases it.
+    ///
+    /// The peer's [`MpUdpRead::recv`] returns [`crate::read::RecvError::Closed`] once it has handed out everything sent before.
+    pub async fn close(mut self) -> io::Result<()> {
+        self.write.shutdown().await
+    }
+    /// Per-path counters and estimates of the session.
+    pub fn stats(&self) -> SessionStats {
+        self.write.stats()
+    }
+    /// Path changes as they happen, for reacting to paths degrading without polling.
+    ///
+    /// Receivers that fall behind skip the oldest events.
+    pub fn events(&self) -> broadcast::Receiver<PathEvent> {
+        self.read.events()
+    }
+    /// Liveness of each path, updated as paths fall silent, die and come back.
+    pub fn path_states(&self) -> watch::Receiver<Vec<PathState>> {
+        self.read.path_states()
     }
     pub fn split_mut(&mut self) -> (&mut MpUdpRead, &mut MpUdpWrite) {
         (&mut self.read, &mut self.write)
@@ -29,41 +151,302 @@ impl MpUdpConn {
     pub fn into_split(self) -> (MpUdpRead, MpUdpWrite) {
         (self.read, self.write)
     }
-    pub async fn connect(addrs: impl Iterator<Item = SocketAddr>) -> io::Result<Self> {
+    /// Opens one path per address with the given role and waits until the listener has acknowledged all of them.
+    ///
+    /// `Init` is retransmitted on every unacknowledged path until the handshake timeout elapses, after which [`io::ErrorKind::TimedOut`] is returned.
+    ///
+    /// A listener that accepts the session without some paths ends the wait early; those paths keep trying to join it for another handshake timeout,
+    /// and the ones that fail to are recorded in the event log.
+    pub async fn connect(
+        addrs: impl Iterator<Item = (SocketAddr, PathRole)>,
+        config: MpUdpConfig,
+    ) -> io::Result<Self> {
         let mut sockets = vec![];
-        for addr in addrs {
-            let any = match addr {
-                SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
-                SocketAddr::V6(_) => {
-                    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0))
-                }
-            };
-            let socket = UdpSocket::bind(any).await?;
-            socket.connect(addr).await?;
+        let mut roles = vec![];
+        for (addr, role) in addrs {
+            let socket = connected_socket(addr).await?;
             sockets.push(Arc::new(socket));
+            roles.push(role);
         }
         let conns = NonZeroUsize::new(sockets.len())
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "zero addresses"))?;
         let session = Session::random();
-        let init = Init::new(session, conns);
-        let with_payload = false;
-        let header = Header::new(init, with_payload);
-        let header = header.encode();
-        for socket in &sockets {
-            socket.send(&header).await?;
+        let init = Init::new(session, conns, Features::supported());
+        let log = EventLog::open(config.session().event_sink(), session, "client");
+        let handshake = handshake(&sockets, init, &config, &log).await;
+        #[cfg(feature = "prometheus")]
+        if let Some(registry) = config.metrics() {
+            registry.connect_result(&handshake);
         }
+        let (features, secret, accepted) = handshake?;
+        let side = Side::Client(init, secret);
+        let next_path = path_id(sockets.len())?;
         let mut write = vec![];
         let mut read = vec![];
-        let stats = new_stats(sockets.len());
-        for socket in sockets {
-            let sender = UdpSender::Client(Arc::clone(&socket));
-            write.push(sender);
-            let recver = UdpRecver::from_client(socket);
+        let mut accepted_roles = vec![];
+        let mut late = vec![];
+        let session = config.session();
+        for (i, (socket, role)) in sockets.into_iter().zip(roles).enumerate() {
+            let path = path_id(i)?;
+            if !accepted.contains(&i) {
+                late.push((path, socket, role));
+                continue;
+            }
+            let sender = UdpSender::from_client(Arc::clone(&socket), path)?;
+            write.push(Arc::new(sender));
+            let recver = UdpRecver::from_client(socket, config.packet_buffer_len());
             read.push(recver);
+            accepted_roles.push(role);
+        }
+        let stats = new_stats(accepted_roles.into_iter(), session.clock().now());
+        let scheduler = session.new_scheduler().build(stats.len());
+        let source = StatsSource::new(
+            stats,
+            write,
+            session.latency_histogram(),
+            Arc::clone(session.clock()),
+        );
+        #[cfg(feature = "prometheus")]
+        if let Some(registry) = config.metrics() {
+            registry.register_session(init.session(), side.vantage_point(), &source);
+        }
+        let read = MpUdpRead::new(
+            read,
+            Arc::clone(&source),
+            side,
+            features,
+            session,
+            log.clone(),
+        );
+        let clock = Arc::clone(session.clock());
+        let write = MpUdpWrite::new(source, side, features, scheduler, clock, log.clone());
+        let mut conn = Self::new(read, write, features, session.clone());
+        let mut late_paths = JoinSet::new();
+        if features.contains(Features::PATH_CHANGES) {
+            for (path, socket, role) in late {
+                let joiner = conn.read.joiner().clone();
+                let config = config.clone();
+                let log = log.clone();
+                late_paths.spawn(async move {
+                    let res = join_path(&joiner, socket, init, secret, path, role, &config).await;
+                    if let Err(e) = res {
+                        log.emit("path_join_failed", |f| {
+                            f.num("path", path).str("error", e);
+                        });
+                    }
+                });
+            }
+        }
+        conn.client = Some(Client {
+            init,
+            secret,
+            config,
+            next_path,
+            _late_paths: late_paths,
+        });
+        Ok(conn)
+    }
+}
+
+fn path_id(path: usize) -> io::Result<u32> {
+    u32::try_from(path).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many paths"))
+}
+async fn connected_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let any = match addr {
+        SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
+    };
+    let socket = UdpSocket::bind(any).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// What a path reports during the handshake.
+enum Handshake {
+    Acked(InitAck),
+    /// The listener accepted the session with the paths of these ids.
+    Established(Vec<u32>),
+}
+/// Returns the features the listener settled on, the secret of the session and the paths it accepted the session with.
+async fn handshake(
+    sockets: &[Arc<UdpSocket>],
+    init: Init,
+    config: &MpUdpConfig,
+    log: &EventLog,
+) -> io::Result<(Features, Secret, Vec<usize>)> {
+    let timeout = config.handshake_timeout();
+    let retransmit_interval = config.init_retransmit_interval();
+    let buffer_len = config.packet_buffer_len();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut handshakes = JoinSet::new();
+    for (i, socket) in sockets.iter().enumerate() {
+        let path = path_id(i)?;
+        let socket = Arc::clone(socket);
+        let log = log.clone();
+        let tx = tx.clone();
+        handshakes.spawn(async move {
+            let ack =
+                handshake_path(&socket, init, path, Kind::Init, &[], retransmit_interval).await;
+            log.emit("handshake_packet_received", |f| {
+                f.str("kind", format_args!("{:?}", Kind::InitAck))
+                    .num("path", i);
+            });
+            let _ = tx.send(Handshake::Acked(ack));
+            // Only listeners that may accept the session without some paths tell of it
+            if !ack.features().contains(Features::PARTIAL) {
+                return;
+            }
+            let paths = established(&socket, init, path, retransmit_interval, buffer_len).await;
+            let _ = tx.send(Handshake::Established(paths));
+        });
+    }
+    drop(tx);
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut features = init.features();
+    let mut secret = None;
+    let mut acked = 0;
+    loop {
+        let res = match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(res)) => res,
+            Ok(None) => unreachable!("unacknowledged paths keep trying"),
+            Err(_) => {
+                let unacknowledged = sockets.len() - acked;
+                log.emit("handshake_timed_out", |f| {
+                    f.num("unacknowledged_paths", unacknowledged);
+                });
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "handshake timed out after {timeout:?}: {unacknowledged} of {} paths unacknowledged",
+                        sockets.len()
+                    ),
+                ));
+            }
+        };
+        match res {
+            Handshake::Acked(ack) => {
+                features = features.intersection(ack.features());
+                secret = Some(ack.secret());
+                acked += 1;
+                if acked == sockets.len() {
+                    return Ok((features, ack.secret(), (0..sockets.len()).collect()));
+                }
+            }
+            Handshake::Established(ids) => {
+                let paths = (0..sockets.len())
+                    .filter(|&i| path_id(i).is_ok_and(|path| ids.contains(&path)))
+                    .collect::<Vec<_>>();
+                // Only acknowledged paths hear of the session being established
+                let Some(secret) = secret.filter(|_| !paths.is_empty()) else {
+                    continue;
+                };
+                log.emit("handshake_established_partially", |f| {
+                    f.num("paths", paths.len());
+                });
+                return Ok((features, secret, paths));
+            }
+        }
+    }
+}
+/// Waits on an acknowledged path for the listener to accept the session without some of the other paths.
+///
+/// `Init` is resent every retransmit interval in case the news got lost on the way.
+async fn established(
+    socket: &UdpSocket,
+    init: Init,
+    path: u32,
+    retransmit_interval: Duration,
+    buffer_len: NonZeroUsize,
+) -> Vec<u32> {
+    let header = Header::new(init, path, Kind::Init).encode();
+    let mut buf = vec![0; buffer_len.get()];
+    loop {
+        let news = async {
+            loop {
+                let Ok(n) = socket.recv(&mut buf).await else {
+                    return std::future::pending().await;
+                };
+                let Ok((Kind::Established, body)) = decode_listener_frame(&buf[..n]) else {
+                    continue;
+                };
+                let Ok(paths) = decode_path_ids(body) else {
+                    continue;
+                };
+                return paths;
+            }
+        };
+        if let Ok(paths) = tokio::time::timeout(retransmit_interval, news).await {
+            return paths;
+        }
+        let _ = socket.send(&header).await;
+    }
+}
+/// Opens `path` into the running session over `socket` and waits until the listener has acknowledged it, returning the index of the path.
+async fn join_path(
+    joiner: &PathJoiner,
+    socket: Arc<UdpSocket>,
+    init: Init,
+    secret: Secret,
+    path: u32,
+    role: PathRole,
+    config: &MpUdpConfig,
+) -> io::Result<usize> {
+    let timeout = config.handshake_timeout();
+    let retransmit_interval = config.init_retransmit_interval();
+    let body = secret.encode();
+    let handshake = handshake_path(&socket, init, path, Kind::Join, &body, retransmit_interval);
+    tokio::time::timeout(timeout, handshake)
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("join timed out after {timeout:?}"),
+            )
+        })?;
+    let sender = Arc::new(UdpSender::from_client(Arc::clone(&socket), path)?);
+    let recver = UdpRecver::from_client(socket, config.packet_buffer_len());
+    let now = config.session().clock().now();
+    joiner
+        .join(recver, sender, role, now)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "session is over"))
+}
+/// Sends `kind` with `body` until the listener acknowledges the path; the caller bounds how long that takes.
+///
+/// Socket errors, e.g. from a firewall on the way rejecting the datagrams, only fail the attempt they happen in.
+async fn handshake_path(
+    socket: &UdpSocket,
+    init: Init,
+    path: u32,
+    kind: Kind,
+    body: &[u8],
+    retransmit_interval: Duration,
+) -> InitAck {
+    let mut header = Header::new(init, path, kind).encode().to_vec();
+    header.extend(body);
+    let mut buf = [0; 1 + INIT_ACK_SIZE];
+    loop {
+        let _ = socket.send(&header).await;
+        let ack = async {
+            loop {
+                // Waits out the attempt rather than spinning on an error that sticks
+                let Ok(n) = socket.recv(&mut buf).await else {
+                    return std::future::pending().await;
+                };
+                let Ok((Kind::InitAck, body)) = decode_listener_frame(&buf[..n]) else {
+                    continue;
+                };
+                let Ok(ack) = body.try_into() else {
+                    continue;
+                };
+                let ack = InitAck::decode(ack);
+                if ack.session() != init.session() {
+                    continue;
+                }
+                return ack;
+            }
+        };
+        if let Ok(ack) = tokio::time::timeout(retransmit_interval, ack).await {
+            return ack;
         }
-        let read_with_init = false;
-        let read = MpUdpRead::new(read, stats.clone(), read_with_init);
-        let write = MpUdpWrite::new(write, stats, Some(init));
-        Ok(Self { write, read })
     }
 }
@@ -0,0 +1,159 @@
+const WINDOW: u64 = 1024;
+const WORDS: usize = WINDOW as usize / 64;
+
+/// Remembers which of the last [`WINDOW`] sequence numbers have been seen.
+#[derive(Debug, Clone)]
+pub struct DupWindow {
+    highest: Option<u64>,
+    seen: [u64; WORDS],
+}
+impl DupWindow {
+    pub fn new() -> Self {
+        Self {
+            highest: None,
+            seen: [0; WORDS],
+        }
+    }
+    pub fn insert(&mut self, seq: u64) -> Verdict {
+        let Some(highest) = self.highest else {
+            self.highest = Some(seq);
+            self.set(seq);
+            return Verdict::New;
+        };
+        if highest < seq {
+            if WINDOW <= seq - highest {
+                self.seen = [0; WORDS];
+            } else {
+                for stale in highest + 1..seq {
+                    self.clear(stale);
+                }
+            }
+            self.highest = Some(seq);
+            self.set(seq);
+            return Verdict::New;
+        }
+        if WINDOW <= highest - seq {
+            return Verdict::TooOld;
+        }
+        if self.is_set(seq) {
+            return Verdict::Duplicate;
+        }
+        self.set(seq);
+        Verdict::New
+    }
+
+    /// Whether every sequence number below `end` has been seen, counting those that fell behind the window as seen.
+    pub fn seen_all_below(&self, end: u64) -> bool {
+        let Some(last) = end.checked_sub(1) else {
+            return true;
+        };
+        let Some(highest) = self.highest.filter(|&highest| last <= highest) else {
+            return false;
+        };
+        let oldest = highest.saturating_sub(WINDOW - 1);
+        (oldest..end).all(|seq| self.is_set(seq))
+    }
+
+    fn pos(seq: u64) -> (usize, u64) {
+        let bit = seq % WINDOW;
+        ((bit / 64) as usize, 1 << (bit % 64))
+    }
+    fn set(&mut self, seq: u64) {
+        let (word, mask) = Self::pos(seq);
+        self.seen[word] |= mask;
+    }
+    fn clear(&mut self, seq: u64) {
+        let (word, mask) = Self::pos(seq);
+        self.seen[word] &= !mask;
+    }
+    fn is_set(&self, seq: u64) -> bool {
+        let (word, mask) = Self::pos(seq);
+        self.seen[word] & mask != 0
+    }
+}
+impl Default for DupWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    New,
+    Duplicate,
+    /// Fell behind the window; cannot tell whether it was seen.
+    TooOld,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdicts() {
+        use Verdict::*;
+        let cases: &[&[(u64, Verdict)]] = &[
+            &[(0, New), (0, Duplicate), (1, New), (1, Duplicate)],
+            // Out of order within the window
+            &[
+                (5, New),
+                (3, New),
+                (4, New),
+                (3, Duplicate),
+                (6, New),
+                (4, Duplicate),
+            ],
+            // The oldest number still in the window and the first one out of it
+            &[(WINDOW, New), (1, New), (1, Duplicate), (0, TooOld)],
+            // A jump past the whole window forgets everything before it
+            &[
+                (3, New),
+                (3 + 2 * WINDOW, New),
+                (4 + WINDOW, New),
+                (3, TooOld),
+            ],
+            // Sliding the window clears the bits that wrap around to new numbers
+            &[
+                (3, New),
+                (3 + WINDOW + 3, New),
+                (3 + WINDOW, New),
+                (3 + WINDOW, Duplicate),
+            ],
+            // Starting anywhere
+            &[
+                (u64::MAX - 1, New),
+                (u64::MAX, New),
+                (u64::MAX - 1, Duplicate),
+            ],
+        ];
+        for (i, case) in cases.iter().enumerate() {
+            let mut window = DupWindow::new();
+            for &(seq, verdict) in *case {
+                assert_eq!(window.insert(seq), verdict, "case {i}, seq {seq}");
+            }
+        }
+    }
+
+    #[test]
+    fn seen_all_below() {
+        // Numbers seen, end asked about, answer
+        let cases: &[(&[u64], u64, bool)] = &[
+            (&[], 0, true),
+            (&[], 1, false),
+            (&[0, 1, 2], 3, true),
+            (&[0, 2], 3, false),
+            (&[0, 2], 1, true),
+            (&[1, 2], 3, false),
+            // Numbers behind the window are given up on
+            (&[WINDOW, 1 + WINDOW], 2 + WINDOW, false),
+            (&[2 * WINDOW, 2 * WINDOW + 1], WINDOW + 1, true),
+        ];
+        for &(seen, end, expected) in cases {
+            let mut window = DupWindow::new();
+            for &seq in seen {
+                window.insert(seq);
+            }
+            assert_eq!(window.seen_all_below(end), expected, "{seen:?} below {end}");
+        }
+    }
+}
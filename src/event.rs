@@ -0,0 +1,132 @@
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use primitive::sync::mutex::SpinMutex;
+use tokio::sync::{broadcast, watch};
+
+use crate::{qlog::EventLog, schedule::PathState};
+
+const EVENT_QUEUE: usize = 64;
+
+/// Something that happened to the paths of a session.
+///
+/// `path` is the index of the path in [`crate::stats::SessionStats::paths`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathEvent {
+    /// The path carries traffic again after being declared dead.
+    Up { path: usize },
+    /// The path stopped carrying traffic.
+    Down { path: usize },
+    /// The smoothed RTT moved by more than the configured share since it was last reported.
+    RttChanged { path: usize, srtt: Duration },
+    /// A path joined the session.
+    Added { path: usize },
+    /// A path left the session; later paths keep their index.
+    Removed { path: usize },
+    /// The peer is now reached at `addr` on the path.
+    PeerAddrChanged { path: usize, addr: SocketAddr },
+    /// No path is left to receive on; always the last event.
+    Closed,
+}
+
+/// Fans changes in path state out to the watch channel, the event stream and the event log.
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    states: watch::Sender<Vec<PathState>>,
+    events: broadcast::Sender<PathEvent>,
+    log: EventLog,
+    rtt_change: f64,
+    reported_rtt: SpinMutex<Vec<Option<Duration>>>,
+    open_paths: AtomicUsize,
+}
+impl Notifier {
+    pub fn new(states: Vec<PathState>, rtt_change: f64, log: EventLog) -> Self {
+        let paths = states.len();
+        Self {
+            states: watch::Sender::new(states),
+            events: broadcast::Sender::new(EVENT_QUEUE),
+            log,
+            rtt_change,
+            reported_rtt: SpinMutex::new(vec![None; paths]),
+            open_paths: AtomicUsize::new(paths),
+        }
+    }
+    pub fn log(&self) -> &EventLog {
+        &self.log
+    }
+    pub fn states(&self) -> watch::Receiver<Vec<PathState>> {
+        self.states.subscribe()
+    }
+    pub fn events(&self) -> broadcast::Receiver<PathEvent> {
+        self.events.subscribe()
+    }
+    pub fn state(&self, path: usize, change: Option<PathState>) {
+        let Some(state) = change else {
+            return;
+        };
+        self.states.send_modify(|states| states[path] = state);
+        self.log.emit("path_state_updated", |f| {
+            f.num("path", path).str("state", format_args!("{state:?}"));
+        });
+        match state {
+            PathState::Dead => self.send(PathEvent::Down { path }),
+            PathState::Revived => self.send(PathEvent::Up { path }),
+            PathState::Retired => self.send(PathEvent::Removed { path }),
+            PathState::Active | PathState::Suspect => (),
+        }
+    }
+    /// Starts tracking a path that joined the session.
+    pub fn added(&self, path: usize, state: PathState) {
+        self.states.send_modify(|states| {
+            // Joins racing each other may arrive out of order
+            if states.len() <= path {
+                states.resize(path + 1, state);
+            }
+            states[path] = state;
+        });
+        {
+            let mut reported_rtt = self.reported_rtt.lock();
+            if reported_rtt.len() <= path {
+                reported_rtt.resize(path + 1, None);
+            }
+        }
+        self.open_paths.fetch_add(1, Ordering::AcqRel);
+        self.log.emit("path_added", |f| {
+            f.num("path", path);
+        });
+        self.send(PathEvent::Added { path });
+    }
+    pub fn rtt(&self, path: usize, srtt: Duration) {
+        {
+            let mut reported_rtt = self.reported_rtt.lock();
+            let reported = &mut reported_rtt[path];
+            if let Some(reported) = reported {
+                let change = reported.abs_diff(srtt).as_secs_f64() / reported.as_secs_f64();
+                if change <= self.rtt_change {
+                    return;
+                }
+            }
+            *reported = Some(srtt);
+        }
+        self.send(PathEvent::RttChanged { path, srtt });
+    }
+    pub fn migrated(&self, path: usize, addr: SocketAddr) {
+        self.log.emit("path_migrated", |f| {
+            f.num("path", path).str("peer_addr", addr);
+        });
+        self.send(PathEvent::PeerAddrChanged { path, addr });
+    }
+    /// Announces the session closed once every path has stopped receiving.
+    pub fn path_closed(&self) {
+        if self.open_paths.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.send(PathEvent::Closed);
+        }
+    }
+    pub fn send(&self, event: PathEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+}
@@ -1,7 +1,18 @@
 mod backlog;
+pub mod clock;
+pub mod config;
 pub mod conn;
+mod dedup;
+pub mod event;
 pub mod listen;
 mod message;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+pub mod qlog;
 pub mod read;
-mod schedule;
+mod reorder;
+pub mod schedule;
+pub mod stats;
 pub mod write;
+
+pub use message::Features;
@@ -1,46 +1,59 @@
+#[cfg(feature = "prometheus")]
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{
+    collections::HashMap,
+    hash::RandomState,
     io::{self, Read},
     net::SocketAddr,
-    num::NonZeroUsize,
-    sync::Arc,
-    time::Duration,
+    sync::{Arc, Mutex},
 };
 
 use tokio::{net::UdpSocket, task::JoinSet};
 use udp_listener::{Packet, UtpListener};
 
+#[cfg(feature = "prometheus")]
+use crate::metrics::Registry;
 use crate::{
     backlog::Backlog,
+    config::{MpUdpListenerConfig, SessionConfig},
     conn::MpUdpConn,
-    message::{HEADER_SIZE, Header},
-    read::{MpUdpRead, UdpRecver},
-    schedule::new_stats,
+    message::{
+        Features, HEADER_SIZE, Header, InitAck, Kind, Secret, Session, Side, encode_path_ids,
+    },
+    qlog::EventLog,
+    read::{MpUdpRead, PathJoiner, UdpRecver},
+    schedule::{PathRole, new_stats},
+    stats::StatsSource,
     write::{MpUdpWrite, UdpSender},
 };
 
-const BACKLOG_TIMEOUT: Duration = Duration::from_secs(60);
-const BACKLOG_MAX: usize = 64;
-
 #[derive(Debug)]
 pub struct MpUdpListener {
     listeners: Vec<Listener>,
+    config: MpUdpListenerConfig,
     complete: tokio::sync::mpsc::Receiver<io::Result<MpUdpConn>>,
     _backlog_handling: JoinSet<()>,
 }
 impl MpUdpListener {
     pub async fn bind(
-        addrs: impl Iterator<Item = SocketAddr>,
-        max_session_conns: NonZeroUsize,
-        dispatcher_buffer_size: NonZeroUsize,
+        addrs: impl Iterator<Item = (SocketAddr, PathRole)>,
+        config: MpUdpListenerConfig,
     ) -> io::Result<Self> {
+        let max_session_conns = config.max_session_conns();
+        let offered = match config.partial_accept() {
+            Some(_) => Features::supported(),
+            None => Features::supported().difference(Features::PARTIAL),
+        };
         let mut listeners = vec![];
-        for addr in addrs {
+        for (addr, role) in addrs {
             let socket = UdpSocket::bind(addr).await?;
             let addr = socket.local_addr().unwrap();
-            let listener = UtpListener::new_identity_dispatch(socket, dispatcher_buffer_size);
+            let listener =
+                UtpListener::new_identity_dispatch(socket, config.dispatcher_buffer_size());
             listeners.push(Listener {
                 listener: Arc::new(listener),
                 local_addr: addr,
+                role,
             });
         }
         if listeners.is_empty() {
@@ -49,73 +62,278 @@ impl MpUdpListener {
                 "number of addresses cannot be zero",
             ));
         }
-        let backlog = Backlog::new(max_session_conns);
+        let backlog: Backlog<Session, PendingPath> = Backlog::new(config.backlog_max());
         let backlog = Arc::new(backlog);
+        let counters = Arc::new(ListenerCounters::default());
+        // Accepted sessions that paths may still join; a blocking lock since sessions are started while holding it
+        let sessions: Arc<Mutex<HashMap<Session, PathJoiner>>> = Default::default();
+        let (tx, rx) = tokio::sync::mpsc::channel(config.backlog_max().get());
+        let establish = Establish {
+            sessions: Arc::clone(&sessions),
+            counters: Arc::clone(&counters),
+            config: config.session().clone(),
+            #[cfg(feature = "prometheus")]
+            registry: config.metrics().cloned(),
+            keys: RandomState::new(),
+            complete: tx,
+        };
         let mut backlog_handling = JoinSet::new();
         backlog_handling.spawn({
             let backlog = Arc::clone(&backlog);
+            let establish = establish.clone();
+            let backlog_timeout = config.backlog_timeout();
+            let partial_accept = config.partial_accept();
+            let tick = partial_accept.map_or(backlog_timeout, |partial| {
+                partial.grace_period.min(backlog_timeout)
+            }) / 2;
             async move {
+                let Establish {
+                    sessions, counters, ..
+                } = &establish;
                 loop {
-                    tokio::time::sleep(BACKLOG_TIMEOUT / 2).await;
-                    backlog.clean(BACKLOG_TIMEOUT);
+                    tokio::time::sleep(tick).await;
+                    let now = establish.config.clock().now();
+                    let timed_out = backlog.clean(now, backlog_timeout);
+                    counters.backlog_timeouts.add(timed_out.len());
+                    sessions
+                        .lock()
+                        .unwrap()
+                        .retain(|_, joiner| !joiner.is_closed());
+                    for (_, paths) in timed_out {
+                        paths[0].log.emit("backlog_timed_out", |_| ());
+                    }
+                    let partial = partial_accept.map(|partial| {
+                        // Sessions leave the backlog and start running under one lock, so an `Init` of a missing path always finds one of them
+                        let mut sessions = sessions.lock().unwrap();
+                        // Only clients that understand `Established` can be told which paths made it
+                        let taken = backlog.take_partial(
+                            now,
+                            partial.grace_period,
+                            partial.min_paths,
+                            |paths: &[PendingPath]| paths[0].features.contains(Features::PARTIAL),
+                        );
+                        taken
+                            .into_iter()
+                            .map(|(session, paths)| {
+                                paths[0].log.emit("backlog_completed_partially", |f| {
+                                    f.num("paths", paths.len());
+                                });
+                                let ack = establish.ack(session, paths[0].features);
+                                let ids = paths.iter().map(|path| path.write.path_id());
+                                let mut frame = vec![];
+                                Side::Server(ack).encode_frame(
+                                    Kind::Established,
+                                    &encode_path_ids(ids),
+                                    &mut frame,
+                                );
+                                let writes = paths.iter().map(|path| Arc::clone(&path.write));
+                                let writes = writes.collect::<Vec<_>>();
+                                let conn = establish.session(session, paths, &mut sessions);
+                                (frame, writes, conn)
+                            })
+                            .collect::<Vec<_>>()
+                    });
+                    counters.backlog_len.set(backlog.len());
+                    for (mut frame, writes, conn) in partial.into_iter().flatten() {
+                        for write in &writes {
+                            let _ = write.send(&mut frame).await;
+                        }
+                        counters.accepted_partially.add(1);
+                        if !establish.complete(conn).await {
+                            return;
+                        }
+                    }
                 }
             }
         });
-        let (tx, rx) = tokio::sync::mpsc::channel(BACKLOG_MAX);
         for listener in &listeners {
+            let role = listener.role;
+            let local_addr = listener.local_addr;
             let listener = Arc::clone(&listener.listener);
             let backlog = Arc::clone(&backlog);
-            let complete = tx.clone();
+            let counters = Arc::clone(&counters);
+            let sessions = Arc::clone(&sessions);
+            let session_config = config.session().clone();
+            let establish = establish.clone();
             backlog_handling.spawn(async move {
                 loop {
-                    let mut conn = match listener.accept().await {
+                    let conn = match listener.accept().await {
                         Ok(x) => x,
                         Err(e) => {
-                            if complete.send(Err(e)).await.is_err() {
+                            if establish.complete.send(Err(e)).await.is_err() {
                                 break;
                             }
                             continue;
                         }
                     };
-                    let pkt = conn.read().recv().recv().await.unwrap();
+                    let (mut read, write) = conn.split();
+                    let pkt = read.recv().recv().await.unwrap();
                     let mut rdr = io::Cursor::new(&pkt[..]);
                     let mut header = [0; HEADER_SIZE];
                     if rdr.read_exact(&mut header).is_err() {
+                        counters.rejected_malformed.add(1);
                         continue;
                     }
                     let Ok(header) = Header::decode(header) else {
+                        counters.rejected_malformed.add(1);
                         continue;
                     };
                     let session = header.init().session();
+                    let running = sessions.lock().unwrap().get(&session).cloned();
+                    let features = header.init().features().intersection(offered);
+                    let ack = establish.ack(session, features);
+                    let side = Side::Server(ack);
+                    // Only sessions that are running or got into the backlog are logged, bounding the files open at a time
+                    let open_log = || {
+                        EventLog::open(session_config.event_sink(), session, side.vantage_point())
+                    };
+                    let rebound = !matches!(header.kind(), Kind::Init | Kind::Join);
+                    if let Some(joiner) = running.as_ref().filter(|_| rebound) {
+                        // A NAT on the way gave the client a new address for a path of the session
+                        counters.rebinds.add(1);
+                        // What the datagram claims does not count, only what the session settled on
+                        if !joiner.features().contains(Features::MIGRATION) {
+                            continue;
+                        }
+                        let write = UdpSender::from_server(write, local_addr, header.path());
+                        let peer_addr = write.peer_addr();
+                        // The datagram that revealed the new address is counted and handed out with the rest
+                        let read = UdpRecver::from_server(read, Some(pkt));
+                        let Some(path) = joiner.migrate(header.path(), read, write) else {
+                            continue;
+                        };
+                        open_log().emit("path_migration_started", |f| {
+                            f.num("path", path).str("peer_addr", peer_addr);
+                        });
+                        continue;
+                    }
+                    let received = pkt.len();
+                    // Only the client holding the secret may add paths to a running session
+                    let proven = ack.secret().strip(&pkt[HEADER_SIZE..]) == Some(&[]);
+                    // `MpUdpConn::connect` waits for `InitAck` before sending data, but other clients may open a path with it;
+                    // the datagrams that follow wait in the dispatcher queue of the path until the session completes
+                    let early = match header.kind() {
+                        Kind::Init | Kind::Join => None,
+                        Kind::Data => Some(pkt),
+                        Kind::InitAck
+                        | Kind::Ping
+                        | Kind::Pong
+                        | Kind::Close
+                        | Kind::Keepalive
+                        | Kind::Retire
+                        | Kind::Challenge
+                        | Kind::Response
+                        | Kind::Established => {
+                            counters.rejected_malformed.add(1);
+                            continue;
+                        }
+                    };
                     let conns = header.init().conns();
+                    let handshake_packet_received = |log: &EventLog| {
+                        log.emit("handshake_packet_received", |f| {
+                            f.str("kind", format_args!("{:?}", header.kind()))
+                                .str("local_addr", local_addr)
+                                .num("paths", conns);
+                        });
+                    };
+                    let mut frame = vec![];
+                    if header.kind() == Kind::Join || running.is_some() {
+                        // An `Init` of a session already running is from a path it was accepted without, which joins it later
+                        if header.kind() == Kind::Init {
+                            continue;
+                        }
+                        let joiner = running.filter(|_| proven);
+                        let Some(joiner) = joiner else {
+                            counters.rejected_unknown_session.add(1);
+                            continue;
+                        };
+                        let log = open_log();
+                        handshake_packet_received(&log);
+                        if max_session_conns.get() <= joiner.live_paths() {
+                            counters.rejected_too_many_paths.add(1);
+                            log.emit("handshake_rejected", |f| {
+                                f.str("reason", "too_many_paths");
+                            });
+                            continue;
+                        }
+                        let write =
+                            Arc::new(UdpSender::from_server(write, local_addr, header.path()));
+                        credit(&write, received, features);
+                        let read = UdpRecver::from_server(read, None);
+                        let now = session_config.clock().now();
+                        if joiner.join(read, Arc::clone(&write), role, now).is_none() {
+                            counters.rejected_unknown_session.add(1);
+                            log.emit("handshake_rejected", |f| {
+                                f.str("reason", "unknown_session");
+                            });
+                            continue;
+                        }
+                        counters.joined.add(1);
+                        // The client retransmits `Join` on this path until it sees this
+                        side.encode_frame(Kind::InitAck, &ack.encode(), &mut frame);
+                        let _ = write.send(&mut frame).await;
+                        continue;
+                    }
                     if max_session_conns < conns {
+                        counters.rejected_too_many_paths.add(1);
                         continue;
                     }
-                    let Some(conns) = backlog.handle(session, conn, conns) else {
+                    if !backlog.admits(&session) {
+                        counters.rejected_backlog_full.add(1);
                         continue;
-                    };
-                    let stats = new_stats(conns.len());
-                    let mut read = vec![];
-                    let mut write = vec![];
-                    for conn in conns {
-                        let (r, w) = conn.split();
-                        let r = UdpRecver::Server(r);
-                        read.push(r);
-                        let w = UdpSender::Server(w);
-                        write.push(w);
                     }
-                    let with_header = true;
-                    let read = MpUdpRead::new(read, stats.clone(), with_header);
-                    let write = MpUdpWrite::new(write, stats, None);
-                    let conn = MpUdpConn::new(read, write);
-                    if complete.send(Ok(conn)).await.is_err() {
+                    let write = Arc::new(UdpSender::from_server(write, local_addr, header.path()));
+                    // Only addresses that show up once the session runs have to prove themselves
+                    write.trust();
+                    let log = open_log();
+                    let path = PendingPath {
+                        read: UdpRecver::from_server(read, early),
+                        write: Arc::clone(&write),
+                        role,
+                        features,
+                        log: log.clone(),
+                    };
+                    let now = session_config.clock().now();
+                    let conn = {
+                        // Same lock as the sessions leaving the backlog early, which this one may have just done
+                        let mut sessions = sessions.lock().unwrap();
+                        if sessions.contains_key(&session) {
+                            continue;
+                        }
+                        let conns = backlog.handle(session, path, conns, now);
+                        counters.backlog_len.set(backlog.len());
+                        let Ok(conns) = conns else {
+                            counters.rejected_backlog_full.add(1);
+                            continue;
+                        };
+                        handshake_packet_received(&log);
+                        conns.map(|conns| {
+                            log.emit("backlog_completed", |f| {
+                                f.num("paths", conns.len());
+                            });
+                            establish.session(session, conns, &mut sessions)
+                        })
+                    };
+                    // The client retransmits `Init` on this path until it sees this
+                    side.encode_frame(Kind::InitAck, &ack.encode(), &mut frame);
+                    let _ = write.send(&mut frame).await;
+                    let Some(conn) = conn else {
+                        continue;
+                    };
+                    if !establish.complete(conn).await {
                         break;
                     }
                 }
             });
         }
+        #[cfg(feature = "prometheus")]
+        if let Some(registry) = config.metrics() {
+            let addrs = listeners.iter().map(|listener| listener.local_addr);
+            registry.register_listener(addrs, &counters);
+        }
         Ok(Self {
             listeners,
+            config,
             complete: rx,
             _backlog_handling: backlog_handling,
         })
@@ -131,13 +349,560 @@ impl MpUdpListener {
             .expect("senders will never drop proactively")
     }
 
+    pub fn config(&self) -> &MpUdpListenerConfig {
+        &self.config
+    }
+
     pub fn local_addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
         self.listeners.iter().map(|listener| listener.local_addr)
     }
 }
 
+/// A path of a session waiting in the backlog for the others.
+#[derive(Debug)]
+struct PendingPath {
+    read: UdpRecver,
+    write: Arc<UdpSender>,
+    role: PathRole,
+    /// What the session runs with, settled on from the `Init` of the path.
+    features: Features,
+    /// Keeps the file of the session open while it waits.
+    log: EventLog,
+}
+
+/// Turns the paths of a session that left the backlog into a connection for [`MpUdpListener::accept`].
+#[derive(Debug, Clone)]
+struct Establish {
+    sessions: Arc<Mutex<HashMap<Session, PathJoiner>>>,
+    counters: Arc<ListenerCounters>,
+    config: SessionConfig,
+    #[cfg(feature = "prometheus")]
+    registry: Option<Registry>,
+    /// What the secrets of sessions are derived with.
+    keys: RandomState,
+    complete: tokio::sync::mpsc::Sender<io::Result<MpUdpConn>>,
+}
+impl Establish {
+    fn ack(&self, session: Session, features: Features) -> InitAck {
+        InitAck::new(session, features, Secret::derive(&self.keys, session))
+    }
+    /// Starts the session and lets paths join it through `sessions`, which the caller holds locked.
+    fn session(
+        &self,
+        session: Session,
+        paths: Vec<PendingPath>,
+        sessions: &mut HashMap<Session, PathJoiner>,
+    ) -> MpUdpConn {
+        let config = &self.config;
+        let features = paths[0].features;
+        let log = paths[0].log.clone();
+        let side = Side::Server(self.ack(session, features));
+        let scheduler = config.new_scheduler().build(paths.len());
+        let stats = new_stats(paths.iter().map(|path| path.role), config.clock().now());
+        let mut read = vec![];
+        let mut write = vec![];
+        for path in paths {
+            read.push(path.read);
+            write.push(path.write);
+        }
+        let source = StatsSource::new(
+            stats,
+            write,
+            config.latency_histogram(),
+            Arc::clone(config.clock()),
+        );
+        #[cfg(feature = "prometheus")]
+        if let Some(registry) = &self.registry {
+            registry.register_session(session, side.vantage_point(), &source);
+        }
+        let read = MpUdpRead::new(
+            read,
+            Arc::clone(&source),
+            side,
+            features,
+            config,
+            log.clone(),
+        );
+        sessions.insert(session, read.joiner().clone());
+        let clock = Arc::clone(config.clock());
+        let write = MpUdpWrite::new(source, side, features, scheduler, clock, log);
+        self.counters.accepted.add(1);
+        MpUdpConn::new(read, write, features, config.clone())
+    }
+    /// Hands the session to [`MpUdpListener::accept`]; returns `false` once the listener is gone.
+    async fn complete(&self, conn: MpUdpConn) -> bool {
+        self.complete.send(Ok(conn)).await.is_ok()
+    }
+}
+
+/// Lets the first packet of a path joining a running session pay for the answer to it.
+///
+/// Clients without [`Features::MIGRATION`] cannot answer challenges, so their addresses are trusted as before.
+fn credit(write: &UdpSender, received: usize, features: Features) {
+    write.received(received);
+    if !features.contains(Features::MIGRATION) {
+        write.trust();
+    }
+}
+
+/// Outcomes of the handshakes a listener has seen.
+#[derive(Debug, Default)]
+pub(crate) struct ListenerCounters {
+    pub accepted: Counter,
+    /// Sessions accepted before all of their paths opened; also counted in `accepted`.
+    pub accepted_partially: Counter,
+    /// Paths opened with something other than a well-formed `Init` or `Data`.
+    pub rejected_malformed: Counter,
+    pub rejected_too_many_paths: Counter,
+    pub rejected_backlog_full: Counter,
+    /// Incomplete sessions dropped for not opening all their paths in time.
+    pub backlog_timeouts: Counter,
+    pub backlog_len: Counter,
+    /// Paths that joined a session after it was accepted.
+    pub joined: Counter,
+    /// `Join`s for sessions the listener is not running or without their secret.
+    pub rejected_unknown_session: Counter,
+    /// Paths of running sessions heard from at a new client address.
+    pub rebinds: Counter,
+}
+/// Only counts with the `prometheus` feature, which is all that reads it; otherwise it takes no space and updating it does nothing.
+#[derive(Debug, Default)]
+pub(crate) struct Counter(#[cfg(feature = "prometheus")] AtomicU64);
+impl Counter {
+    fn add(&self, n: usize) {
+        #[cfg(feature = "prometheus")]
+        self.0.fetch_add(n as u64, Ordering::Relaxed);
+        #[cfg(not(feature = "prometheus"))]
+        let _ = n;
+    }
+    fn set(&self, n: usize) {
+        #[cfg(feature = "prometheus")]
+        self.0.store(n as u64, Ordering::Relaxed);
+        #[cfg(not(feature = "prometheus"))]
+        let _ = n;
+    }
+    #[cfg(feature = "prometheus")]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Debug)]
 struct Listener {
     pub listener: Arc<UtpListener<UdpSocket, SocketAddr, Packet>>,
     pub local_addr: SocketAddr,
+    /// Role of the session paths coming in through this listener.
+    pub role: PathRole,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use super::*;
+    use crate::{
+        clock::{Clock, ManualClock},
+        config::{Liveness, MpUdpConfig, PartialAccept},
+        event::PathEvent,
+        message::{
+            INIT_ACK_SIZE, Init, decode_data, decode_listener_frame, encode_close, stamp_path,
+        },
+        read::RecvError,
+        schedule::PathState,
+    };
+
+    async fn bind(paths: usize, config: MpUdpListenerConfig) -> MpUdpListener {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let addrs = std::iter::repeat_n((addr, PathRole::Primary), paths);
+        MpUdpListener::bind(addrs, config).await.unwrap()
+    }
+    async fn client_socket(listener_addr: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(listener_addr).await.unwrap();
+        socket
+    }
+
+    #[tokio::test]
+    async fn deliver_data_opening_a_path() {
+        let mut listener = bind(2, MpUdpListenerConfig::default()).await;
+        let addrs = listener.local_addrs().collect::<Vec<_>>();
+        let init = Init::new(
+            Session::random(),
+            NonZeroUsize::new(2).unwrap(),
+            Features::supported(),
+        );
+        // Data frames do not carry the secret, which the client has yet to learn
+        let unknown = Secret::derive(&RandomState::new(), init.session());
+        let side = Side::Client(init, unknown);
+        let a = client_socket(addrs[0]).await;
+        let b = client_socket(addrs[1]).await;
+        // The first path opens with data while the second has yet to arrive
+        let mut frame = vec![];
+        for (seq, payload) in [b"first", b"queue"].into_iter().enumerate() {
+            side.encode_data(seq as u64, payload, &mut frame);
+            stamp_path(&mut frame, 0);
+            a.send(&frame).await.unwrap();
+        }
+        let mut buf = [0; 1 + INIT_ACK_SIZE];
+        let n = a.recv(&mut buf).await.unwrap();
+        assert!(matches!(
+            decode_listener_frame(&buf[..n]),
+            Ok((Kind::InitAck, _))
+        ));
+        let header = Header::new(init, 1, Kind::Init).encode();
+        b.send(&header).await.unwrap();
+        let mut conn = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let (read, _) = conn.split_mut();
+        let mut buf = [0; 16];
+        for expected in [b"first", b"queue"] {
+            let n = read.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], expected);
+        }
+    }
+
+    /// Opens a session of one path from a raw client socket; returns the accepted connection, the socket and the framing of the client.
+    async fn open_raw(listener: &mut MpUdpListener) -> (MpUdpConn, UdpSocket, Side) {
+        let addr = listener.local_addrs().next().unwrap();
+        let init = Init::new(Session::random(), NonZeroUsize::MIN, Features::supported());
+        let socket = client_socket(addr).await;
+        socket
+            .send(&Header::new(init, 0, Kind::Init).encode())
+            .await
+            .unwrap();
+        let mut buf = [0; 1 + INIT_ACK_SIZE];
+        let ack = loop {
+            let n = socket.recv(&mut buf).await.unwrap();
+            if let Ok((Kind::InitAck, body)) = decode_listener_frame(&buf[..n]) {
+                break InitAck::decode(body.try_into().unwrap());
+            }
+        };
+        let conn = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        (conn, socket, Side::Client(init, ack.secret()))
+    }
+
+    #[tokio::test]
+    async fn close_after_draining() {
+        let drain = Duration::from_millis(300);
+        let session = SessionConfig::builder()
+            .close_drain_timeout(drain)
+            .build()
+            .unwrap();
+        let config = MpUdpListenerConfig::builder()
+            .session(session)
+            .build()
+            .unwrap();
+        let mut listener = bind(1, config).await;
+        // Whether the peer sent a datagram that never arrives
+        for lost in [false, true] {
+            let (mut conn, socket, side) = open_raw(&mut listener).await;
+            let frame_of = |kind, body: &[u8]| {
+                let mut frame = vec![];
+                side.encode_frame(kind, body, &mut frame);
+                stamp_path(&mut frame, 0);
+                frame
+            };
+            // Knowing the session number is not enough to close it
+            let Side::Client(init, _) = side else {
+                unreachable!("raw paths are clients");
+            };
+            let mut forged = init.session().inner().to_be_bytes().to_vec();
+            forged.extend(0_u64.to_be_bytes());
+            socket.send(&frame_of(Kind::Close, &forged)).await.unwrap();
+            // The close overtakes the data sent before it
+            let end = if lost { 3 } else { 2 };
+            let body = encode_close(side.secret(), end);
+            socket.send(&frame_of(Kind::Close, &body)).await.unwrap();
+            let mut frame = vec![];
+            for (seq, payload) in [b"first", b"later"].into_iter().enumerate() {
+                side.encode_data(seq as u64, payload, &mut frame);
+                stamp_path(&mut frame, 0);
+                socket.send(&frame).await.unwrap();
+            }
+            let (read, _) = conn.split_mut();
+            let mut buf = [0; 16];
+            for expected in [b"first", b"later"] {
+                let n = read.recv(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], expected);
+            }
+            let start = tokio::time::Instant::now();
+            let res = tokio::time::timeout(Duration::from_secs(5), read.recv(&mut buf)).await;
+            assert!(matches!(res, Ok(Err(RecvError::Closed))));
+            assert_eq!(lost, drain / 2 <= start.elapsed(), "lost: {lost}");
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_follows_the_clock() {
+        let clock = Arc::new(ManualClock::new(std::time::Instant::now()));
+        let idle_timeout = Duration::from_secs(30);
+        let session = SessionConfig::builder()
+            .clock(Arc::clone(&clock) as Arc<dyn Clock>)
+            .idle_timeout(Some(idle_timeout))
+            .build()
+            .unwrap();
+        let config = MpUdpListenerConfig::builder()
+            .session(session)
+            .build()
+            .unwrap();
+        let mut listener = bind(1, config).await;
+        let (mut conn, _socket, _) = open_raw(&mut listener).await;
+        let (read, _) = conn.split_mut();
+        let mut buf = [0; 16];
+        let recv = read.recv(&mut buf);
+        tokio::pin!(recv);
+        let waiting = tokio::time::timeout(Duration::from_millis(100), &mut recv).await;
+        assert!(waiting.is_err());
+        clock.advance(idle_timeout);
+        let res = tokio::time::timeout(Duration::from_secs(5), recv).await;
+        assert!(matches!(res, Ok(Err(RecvError::TimedOut))));
+    }
+
+    #[tokio::test]
+    async fn join_with_secret_and_retire() {
+        let probe_interval = Duration::from_millis(100);
+        let session = SessionConfig::builder()
+            .liveness(Liveness {
+                probe_interval,
+                ..Liveness::default()
+            })
+            .build()
+            .unwrap();
+        let config = MpUdpListenerConfig::builder()
+            .max_session_conns(NonZeroUsize::new(2).unwrap())
+            .session(session)
+            .build()
+            .unwrap();
+        let mut listener = bind(1, config).await;
+        let addr = listener.local_addrs().next().unwrap();
+        let (mut conn, _a, side) = open_raw(&mut listener).await;
+        let Side::Client(init, secret) = side else {
+            unreachable!("raw paths are clients");
+        };
+        // Whether the listener acknowledges a `Join` of `path` with `body` on `socket`
+        let join = async |socket: &UdpSocket, path, body: &[u8]| {
+            let mut frame = Header::new(init, path, Kind::Join).encode().to_vec();
+            frame.extend(body);
+            socket.send(&frame).await.unwrap();
+            let mut buf = [0; 1 + INIT_ACK_SIZE];
+            let acked = async {
+                loop {
+                    let n = socket.recv(&mut buf).await.unwrap();
+                    if let Ok((Kind::InitAck, _)) = decode_listener_frame(&buf[..n]) {
+                        return;
+                    }
+                }
+            };
+            tokio::time::timeout(Duration::from_millis(300), acked)
+                .await
+                .is_ok()
+        };
+        let b = client_socket(addr).await;
+        let forged = init.session().inner().to_be_bytes();
+        assert!(!join(&b, 1, &forged).await);
+        assert!(join(&b, 1, &secret.encode()).await);
+        let c = client_socket(addr).await;
+        assert!(!join(&c, 2, &secret.encode()).await);
+        // A retired path leaves room for another
+        let (_, write) = conn.split_mut();
+        assert_eq!(
+            write.retire_path(1).await.unwrap(),
+            Some(PathState::Retired)
+        );
+        assert!(join(&c, 2, &secret.encode()).await);
+        // `Retire` is resent until it is echoed
+        let retires = async |socket: &UdpSocket, wait| {
+            let mut buf = [0; 64];
+            let mut retires = 0;
+            let count = async {
+                loop {
+                    let n = socket.recv(&mut buf).await.unwrap();
+                    if let Ok((Kind::Retire, body)) = decode_listener_frame(&buf[..n]) {
+                        assert_eq!(body, secret.encode());
+                        retires += 1;
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(wait, count).await;
+            retires
+        };
+        assert!(1 < retires(&b, probe_interval * 4).await);
+        let mut frame = vec![];
+        side.encode_frame(Kind::Retire, &secret.encode(), &mut frame);
+        stamp_path(&mut frame, 1);
+        b.send(&frame).await.unwrap();
+        tokio::time::sleep(probe_interval).await;
+        assert_eq!(retires(&b, probe_interval * 4).await, 0);
+    }
+
+    #[tokio::test]
+    async fn keep_data_of_rebound_path() {
+        let mut listener = bind(1, MpUdpListenerConfig::default()).await;
+        let addr = listener.local_addrs().next().unwrap();
+        let (mut conn, _old, side) = open_raw(&mut listener).await;
+        // The path shows up at a new address before it answers the challenge sent there
+        let rebound = client_socket(addr).await;
+        let mut frame = vec![];
+        for (seq, payload) in [b"moved", b"again"].into_iter().enumerate() {
+            side.encode_data(seq as u64, payload, &mut frame);
+            stamp_path(&mut frame, 0);
+            rebound.send(&frame).await.unwrap();
+        }
+        let (read, _) = conn.split_mut();
+        let mut buf = [0; 16];
+        for expected in [b"moved", b"again"] {
+            let n = tokio::time::timeout(Duration::from_secs(5), read.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_right_after_accept() {
+        let mut listener = bind(2, MpUdpListenerConfig::default()).await;
+        let addrs = listener.local_addrs().map(|addr| (addr, PathRole::Primary));
+        let addrs = addrs.collect::<Vec<_>>();
+        let client = tokio::spawn(MpUdpConn::connect(
+            addrs.into_iter(),
+            MpUdpConfig::default(),
+        ));
+        let mut server = listener.accept().await.unwrap();
+        // Far more than the `Init`s of the client would pay for if its addresses had to be validated,
+        // sent before the client may even have heard the last `InitAck`
+        let (_, write) = server.split_mut();
+        for i in 0..20 {
+            write.send(&[i; 100]).await.unwrap();
+        }
+        let mut client = client.await.unwrap().unwrap();
+        let (read, _) = client.split_mut();
+        let mut buf = [0; 128];
+        let mut received = vec![];
+        for _ in 0..20 {
+            let n = tokio::time::timeout(Duration::from_secs(5), read.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(n, 100);
+            received.push(buf[0]);
+        }
+        received.sort();
+        assert_eq!(received, (0..20).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn accept_without_refused_path() {
+        let partial = PartialAccept {
+            grace_period: Duration::from_millis(100),
+            min_paths: NonZeroUsize::new(1).unwrap(),
+        };
+        let config = MpUdpListenerConfig::builder()
+            .partial_accept(Some(partial))
+            .build()
+            .unwrap();
+        let mut listener = bind(1, config).await;
+        let addr = listener.local_addrs().next().unwrap();
+        // Nothing listens there, so the client hears back port unreachable
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let refused = closed.local_addr().unwrap();
+        drop(closed);
+        let addrs = [(addr, PathRole::Primary), (refused, PathRole::Primary)];
+        let client = tokio::spawn(MpUdpConn::connect(
+            addrs.into_iter(),
+            MpUdpConfig::default(),
+        ));
+        let mut server = listener.accept().await.unwrap();
+        let mut client = tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        client.split_mut().1.send(b"hi").await.unwrap();
+        let mut buf = [0; 16];
+        let n = server.split_mut().0.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hi");
+    }
+
+    #[tokio::test]
+    async fn remove_path_right_after_adding_it() {
+        let mut listener = bind(1, MpUdpListenerConfig::default()).await;
+        let addr = listener.local_addrs().next().unwrap();
+        let connect = MpUdpConn::connect(
+            std::iter::once((addr, PathRole::Primary)),
+            MpUdpConfig::default(),
+        );
+        let (client, server) = tokio::join!(connect, listener.accept());
+        let (mut client, _server) = (client.unwrap(), server.unwrap());
+        let path = client.add_path(addr, PathRole::Backup).await.unwrap();
+        client.remove_path(path).await.unwrap();
+        assert_eq!(client.path_states().borrow()[path], PathState::Retired);
+    }
+
+    #[tokio::test]
+    async fn migrate_to_rebound_address() {
+        let mut listener = bind(1, MpUdpListenerConfig::default()).await;
+        let addr = listener.local_addrs().next().unwrap();
+        let (mut conn, _old, side) = open_raw(&mut listener).await;
+        let mut events = conn.events();
+        let rebound = client_socket(addr).await;
+        let frame_of = |kind, body: &[u8]| {
+            let mut frame = vec![];
+            side.encode_frame(kind, body, &mut frame);
+            stamp_path(&mut frame, 0);
+            frame
+        };
+        let mut frame = vec![];
+        side.encode_data(0, b"moved", &mut frame);
+        stamp_path(&mut frame, 0);
+        rebound.send(&frame).await.unwrap();
+        // The datagram that revealed the address pays for the challenge, which goes out right away
+        let mut buf = [0; 64];
+        let token = tokio::time::timeout(Duration::from_millis(500), async {
+            loop {
+                let n = rebound.recv(&mut buf).await.unwrap();
+                if let Ok((Kind::Challenge, token)) = decode_listener_frame(&buf[..n]) {
+                    return token.to_vec();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        // Reaching the address is not enough to take the path over
+        rebound
+            .send(&frame_of(Kind::Response, &token))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(events.try_recv().is_err());
+        let body = [&side.secret().encode(), &token[..]].concat();
+        rebound
+            .send(&frame_of(Kind::Response, &body))
+            .await
+            .unwrap();
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let addr = rebound.local_addr().unwrap();
+        assert_eq!(event, PathEvent::PeerAddrChanged { path: 0, addr });
+        let (_, write) = conn.split_mut();
+        write.send(b"there").await.unwrap();
+        let payload = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let n = rebound.recv(&mut buf).await.unwrap();
+                if let Ok((Kind::Data, body)) = decode_listener_frame(&buf[..n]) {
+                    return decode_data(body).unwrap().1.to_vec();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(payload, b"there");
+    }
 }
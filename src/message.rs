@@ -1,6 +1,9 @@
 use std::{
+    hash::{BuildHasher, RandomState},
     io::{self, Read, Write},
     num::NonZeroUsize,
+    sync::OnceLock,
+    time::{Duration, Instant},
 };
 
 // #[derive(Debug, Clone)]
@@ -9,67 +12,288 @@ use std::{
 //     Some(Init),
 // }
 
-pub const HEADER_SIZE: usize = INIT_SIZE + 1;
+/// Leading bytes of every handshake header; anything else is foreign traffic.
+pub const MAGIC: [u8; 4] = *b"MPUD";
+/// Wire protocol version spoken by this build.
+pub const VERSION: u8 = 3;
+
+pub const PATH_ID_SIZE: usize = 4;
+const PATH_ID_OFFSET: usize = MAGIC.len() + INIT_SIZE;
+pub const HEADER_SIZE: usize = MAGIC.len() + INIT_SIZE + PATH_ID_SIZE + 1;
 pub type HeaderBuf = [u8; HEADER_SIZE];
 #[derive(Debug, Clone, Copy)]
 pub struct Header {
     init: Init,
-    with_payload: bool,
+    path: u32,
+    kind: Kind,
 }
 impl Header {
-    pub fn new(init: Init, with_payload: bool) -> Self {
-        Self { init, with_payload }
+    pub fn new(init: Init, path: u32, kind: Kind) -> Self {
+        Self { init, path, kind }
     }
     pub fn init(&self) -> &Init {
         &self.init
     }
-    pub fn with_payload(&self) -> bool {
-        self.with_payload
+    /// Which path of the client the datagram was sent on, however the address of the client looks by the time it arrives.
+    pub fn path(&self) -> u32 {
+        self.path
+    }
+    pub fn kind(&self) -> Kind {
+        self.kind
     }
 
     pub fn encode(&self) -> HeaderBuf {
         let mut buf = [0; HEADER_SIZE];
         let mut wtr = io::Cursor::new(&mut buf[..]);
+        wtr.write_all(&MAGIC).unwrap();
         let init = self.init.encode();
         wtr.write_all(&init[..]).unwrap();
-        match self.with_payload {
-            true => wtr.write_all(&[1]).unwrap(),
-            false => wtr.write_all(&[0]).unwrap(),
-        }
+        wtr.write_all(&self.path.to_be_bytes()).unwrap();
+        wtr.write_all(&[self.kind.encode()]).unwrap();
         buf
     }
     pub fn decode(buf: HeaderBuf) -> io::Result<Self> {
         let mut rdr = io::Cursor::new(&buf[..]);
+        let mut magic = [0; MAGIC.len()];
+        rdr.read_exact(&mut magic).unwrap();
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad magic"));
+        }
         let mut init = [0; INIT_SIZE];
         rdr.read_exact(&mut init).unwrap();
         let init = Init::decode(init)?;
-        let mut with_payload = [0];
-        rdr.read_exact(&mut with_payload).unwrap();
-        let with_payload = with_payload[0];
-        let with_payload = match with_payload {
-            0 => false,
-            1 => true,
+        let mut path = 0_u32.to_be_bytes();
+        rdr.read_exact(&mut path).unwrap();
+        let path = u32::from_be_bytes(path);
+        let mut kind = [0];
+        rdr.read_exact(&mut kind).unwrap();
+        let kind = Kind::decode(kind[0])?;
+        Ok(Self { init, path, kind })
+    }
+}
+
+/// What the body of a datagram carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Opens a path; no body.
+    Init,
+    /// User payload prefixed with a sequence number shared by all copies of a datagram.
+    Data,
+    /// Listener confirmation of an [`Init`]; body is [`InitAck`].
+    InitAck,
+    /// RTT probe; body is an opaque timestamp of the sender.
+    Ping,
+    /// Answer to a [`Kind::Ping`] echoing its body on the same path.
+    Pong,
+    /// Ends the session; body is the [`Secret`] of the session followed by the sequence number the sender's data ends before.
+    Close,
+    /// Keeps NAT bindings of an idle path open; no body.
+    Keepalive,
+    /// Opens a path into a session the listener has already accepted; body is the [`Secret`] of the session.
+    Join,
+    /// Takes the path it arrives on out of the session and is echoed once by the peer; body is the [`Secret`] of the session.
+    Retire,
+    /// Asks the client to prove it is reachable at the address a path comes from; body is a random [`TokenBuf`].
+    Challenge,
+    /// Answer to a [`Kind::Challenge`] on the same path; body is the [`Secret`] of the session followed by the token.
+    Response,
+    /// Tells the client its session was accepted before all of its paths opened; body is the ids of the paths in it.
+    Established,
+}
+impl Kind {
+    pub fn encode(&self) -> u8 {
+        match self {
+            Kind::Init => 0,
+            Kind::Data => 1,
+            Kind::InitAck => 2,
+            Kind::Ping => 3,
+            Kind::Pong => 4,
+            Kind::Close => 5,
+            Kind::Keepalive => 6,
+            Kind::Join => 7,
+            Kind::Retire => 8,
+            Kind::Challenge => 9,
+            Kind::Response => 10,
+            Kind::Established => 11,
+        }
+    }
+    pub fn decode(byte: u8) -> io::Result<Self> {
+        Ok(match byte {
+            0 => Kind::Init,
+            1 => Kind::Data,
+            2 => Kind::InitAck,
+            3 => Kind::Ping,
+            4 => Kind::Pong,
+            5 => Kind::Close,
+            6 => Kind::Keepalive,
+            7 => Kind::Join,
+            8 => Kind::Retire,
+            9 => Kind::Challenge,
+            10 => Kind::Response,
+            11 => Kind::Established,
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!("unknown with_payload: {with_payload}"),
+                    format!("unknown kind: {byte}"),
                 ));
             }
-        };
-        Ok(Self { init, with_payload })
+        })
+    }
+}
+
+/// Framing of one end of a session.
+///
+/// Client datagrams are prefixed with the full [`Header`] so the listener can tell sessions and paths apart;
+/// listener datagrams only carry the [`Kind`] byte since client sockets are connected.
+///
+/// The path in client headers is left at zero here and filled in with [`stamp_path`] as each copy is sent.
+#[derive(Debug, Clone, Copy)]
+pub enum Side {
+    /// With the secret the listener acknowledged the session with.
+    Client(Init, Secret),
+    Server(InitAck),
+}
+impl Side {
+    /// Name of this end in logs and metrics.
+    pub fn vantage_point(&self) -> &'static str {
+        match self {
+            Side::Client(..) => "client",
+            Side::Server(_) => "server",
+        }
+    }
+    pub fn secret(&self) -> Secret {
+        match self {
+            Side::Client(_, secret) => *secret,
+            Side::Server(ack) => ack.secret(),
+        }
+    }
+    pub fn encode_frame(&self, kind: Kind, body: &[u8], buf: &mut Vec<u8>) {
+        self.encode_prefix(kind, buf);
+        buf.extend(body);
+    }
+    pub fn encode_data(&self, seq: u64, payload: &[u8], buf: &mut Vec<u8>) {
+        self.encode_prefix(Kind::Data, buf);
+        buf.extend(seq.to_be_bytes());
+        buf.extend(payload);
+    }
+    fn encode_prefix(&self, kind: Kind, buf: &mut Vec<u8>) {
+        buf.clear();
+        match self {
+            Side::Client(init, _) => buf.extend(Header::new(*init, 0, kind).encode()),
+            Side::Server(_) => buf.push(kind.encode()),
+        }
+    }
+    pub fn decode_frame<'a>(&self, pkt: &'a [u8]) -> io::Result<(Kind, &'a [u8])> {
+        match self {
+            Side::Client(..) => decode_listener_frame(pkt),
+            Side::Server(ack) => {
+                let mut rdr = io::Cursor::new(pkt);
+                let mut header = [0; HEADER_SIZE];
+                rdr.read_exact(&mut header)?;
+                let header = Header::decode(header)?;
+                if header.init().session() != ack.session() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "foreign session",
+                    ));
+                }
+                Ok((header.kind(), &pkt[HEADER_SIZE..]))
+            }
+        }
+    }
+}
+
+/// Splits a frame from the listener, which needs no session to make sense of, into its kind and body.
+pub fn decode_listener_frame(pkt: &[u8]) -> io::Result<(Kind, &[u8])> {
+    let (&kind, body) = pkt
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty packet"))?;
+    Ok((Kind::decode(kind)?, body))
+}
+
+/// Writes `path` into the [`Header`] of an encoded client frame.
+pub fn stamp_path(frame: &mut [u8], path: u32) {
+    frame[PATH_ID_OFFSET..PATH_ID_OFFSET + PATH_ID_SIZE].copy_from_slice(&path.to_be_bytes());
+}
+
+/// Body of a [`Kind::Established`] frame.
+pub fn encode_path_ids(paths: impl Iterator<Item = u32>) -> Vec<u8> {
+    paths.flat_map(u32::to_be_bytes).collect()
+}
+pub fn decode_path_ids(body: &[u8]) -> io::Result<Vec<u32>> {
+    if !body.len().is_multiple_of(PATH_ID_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated path id",
+        ));
     }
+    Ok(body
+        .chunks_exact(PATH_ID_SIZE)
+        .map(|id| u32::from_be_bytes(id.try_into().unwrap()))
+        .collect())
+}
+
+pub const SEQ_SIZE: usize = 8;
+/// Splits a [`Kind::Data`] body into its sequence number and payload.
+pub fn decode_data(body: &[u8]) -> io::Result<(u64, &[u8])> {
+    let mut rdr = io::Cursor::new(body);
+    let mut seq = 0_u64.to_be_bytes();
+    rdr.read_exact(&mut seq)?;
+    Ok((u64::from_be_bytes(seq), &body[SEQ_SIZE..]))
+}
+
+/// Body of a [`Kind::Close`] frame.
+pub fn encode_close(secret: Secret, end: u64) -> [u8; SECRET_SIZE + SEQ_SIZE] {
+    let mut buf = [0; SECRET_SIZE + SEQ_SIZE];
+    buf[..SECRET_SIZE].copy_from_slice(&secret.encode());
+    buf[SECRET_SIZE..].copy_from_slice(&end.to_be_bytes());
+    buf
+}
+/// The sequence number the data of the peer ends before, if the body proves the frame comes from the peer.
+pub fn decode_close(secret: Secret, body: &[u8]) -> Option<u64> {
+    let end = secret.strip(body)?;
+    Some(u64::from_be_bytes(end.try_into().ok()?))
+}
+
+pub const TOKEN_SIZE: usize = 8;
+pub type TokenBuf = [u8; TOKEN_SIZE];
+
+pub const TIMESTAMP_SIZE: usize = 8;
+pub type TimestampBuf = [u8; TIMESTAMP_SIZE];
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+pub fn encode_timestamp(time: Instant) -> TimestampBuf {
+    let nanos = time.saturating_duration_since(epoch()).as_nanos();
+    u64::try_from(nanos).unwrap_or(u64::MAX).to_be_bytes()
+}
+/// Only the sender of a timestamp can make sense of it.
+pub fn decode_timestamp(body: &[u8]) -> io::Result<Instant> {
+    let mut rdr = io::Cursor::new(body);
+    let mut nanos = 0_u64.to_be_bytes();
+    rdr.read_exact(&mut nanos)?;
+    let nanos = u64::from_be_bytes(nanos);
+    epoch()
+        .checked_add(Duration::from_nanos(nanos))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "timestamp overflow"))
 }
 
-pub const INIT_SIZE: usize = 8 * 2;
+pub const INIT_SIZE: usize = 1 + 4 + 8 * 2;
 pub type InitBuf = [u8; INIT_SIZE];
 #[derive(Debug, Clone, Copy)]
 pub struct Init {
+    features: Features,
     session: Session,
     conns: NonZeroUsize,
 }
 impl Init {
-    pub fn new(session: Session, conns: NonZeroUsize) -> Self {
-        Self { session, conns }
+    pub fn new(session: Session, conns: NonZeroUsize, features: Features) -> Self {
+        Self {
+            features,
+            session,
+            conns,
+        }
     }
     pub fn session(&self) -> Session {
         self.session
@@ -77,10 +301,15 @@ impl Init {
     pub fn conns(&self) -> NonZeroUsize {
         self.conns
     }
+    pub fn features(&self) -> Features {
+        self.features
+    }
 
     pub fn encode(&self) -> InitBuf {
         let mut buf = [0; INIT_SIZE];
         let mut wtr = io::Cursor::new(&mut buf[..]);
+        wtr.write_all(&[VERSION]).unwrap();
+        wtr.write_all(&self.features.bits().to_be_bytes()).unwrap();
         wtr.write_all(&self.session.inner().to_be_bytes()).unwrap();
         wtr.write_all(&(self.conns.get() as u64).to_be_bytes())
             .unwrap();
@@ -88,6 +317,18 @@ impl Init {
     }
     pub fn decode(buf: InitBuf) -> io::Result<Self> {
         let mut rdr = io::Cursor::new(&buf[..]);
+        let mut version = [0];
+        rdr.read_exact(&mut version).unwrap();
+        let version = version[0];
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported version: {version}"),
+            ));
+        }
+        let mut features = 0_u32.to_be_bytes();
+        rdr.read_exact(&mut features).unwrap();
+        let features = Features::from_bits(u32::from_be_bytes(features));
         let mut session = 0_u64.to_be_bytes();
         rdr.read_exact(&mut session).unwrap();
         let session = Session::new(u64::from_be_bytes(session));
@@ -97,7 +338,141 @@ impl Init {
             .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))?;
         let conns = NonZeroUsize::new(conns)
             .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "zero conns"))?;
-        Ok(Self { session, conns })
+        Ok(Self {
+            features,
+            session,
+            conns,
+        })
+    }
+}
+
+/// Optional protocol capabilities.
+///
+/// Each side advertises what it understands in [`Init`]; the session runs with the intersection.
+/// Unknown bits from newer peers are kept on decode and dropped by the intersection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u32);
+impl Features {
+    /// [`Kind::Ping`] and [`Kind::Pong`] frames.
+    pub const PING: Self = Self(1 << 0);
+    /// [`Kind::Close`] frames.
+    pub const CLOSE: Self = Self(1 << 1);
+    /// [`Kind::Keepalive`] frames.
+    pub const KEEPALIVE: Self = Self(1 << 2);
+    /// [`Kind::Join`] and [`Kind::Retire`] frames.
+    pub const PATH_CHANGES: Self = Self(1 << 3);
+    /// [`Kind::Challenge`] and [`Kind::Response`] frames, with which the listener validates client addresses before sending to them freely and lets paths follow a client to a new address.
+    pub const MIGRATION: Self = Self(1 << 4);
+    /// [`Kind::Established`] frames, with which the listener accepts a session before all of its paths have opened.
+    ///
+    /// Only listeners set up to accept sessions partially settle on it, so that clients of others wait for nothing but `InitAck`.
+    pub const PARTIAL: Self = Self(1 << 5);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    /// Every capability this build understands.
+    pub const fn supported() -> Self {
+        Self::PING
+            .union(Self::CLOSE)
+            .union(Self::KEEPALIVE)
+            .union(Self::PATH_CHANGES)
+            .union(Self::MIGRATION)
+            .union(Self::PARTIAL)
+    }
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    pub const fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+    pub const fn union(&self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+    pub const fn difference(&self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+pub const INIT_ACK_SIZE: usize = 8 + 4 + SECRET_SIZE;
+pub type InitAckBuf = [u8; INIT_ACK_SIZE];
+#[derive(Debug, Clone, Copy)]
+pub struct InitAck {
+    session: Session,
+    features: Features,
+    secret: Secret,
+}
+impl InitAck {
+    pub fn new(session: Session, features: Features, secret: Secret) -> Self {
+        Self {
+            session,
+            features,
+            secret,
+        }
+    }
+    pub fn session(&self) -> Session {
+        self.session
+    }
+    /// Features the listener settled on for the session.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+    pub fn secret(&self) -> Secret {
+        self.secret
+    }
+
+    pub fn encode(&self) -> InitAckBuf {
+        let mut buf = [0; INIT_ACK_SIZE];
+        let mut wtr = io::Cursor::new(&mut buf[..]);
+        wtr.write_all(&self.session.inner().to_be_bytes()).unwrap();
+        wtr.write_all(&self.features.bits().to_be_bytes()).unwrap();
+        wtr.write_all(&self.secret.encode()).unwrap();
+        buf
+    }
+    pub fn decode(buf: InitAckBuf) -> Self {
+        let mut rdr = io::Cursor::new(&buf[..]);
+        let mut session = 0_u64.to_be_bytes();
+        rdr.read_exact(&mut session).unwrap();
+        let session = Session::new(u64::from_be_bytes(session));
+        let mut features = 0_u32.to_be_bytes();
+        rdr.read_exact(&mut features).unwrap();
+        let features = Features::from_bits(u32::from_be_bytes(features));
+        let mut secret = [0; SECRET_SIZE];
+        rdr.read_exact(&mut secret).unwrap();
+        let secret = Secret(u64::from_be_bytes(secret));
+        Self {
+            session,
+            features,
+            secret,
+        }
+    }
+}
+
+pub const SECRET_SIZE: usize = 8;
+pub type SecretBuf = [u8; SECRET_SIZE];
+/// Proves that a frame comes from an end of the session rather than from anyone who saw the session number go by.
+///
+/// The listener hands it to the client in [`InitAck`], which only travels to the addresses the paths of the session come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Secret(u64);
+impl Secret {
+    /// Keyed with `keys`, so that the listener recalls the secret of any session without keeping it.
+    pub fn derive(keys: &RandomState, session: Session) -> Self {
+        Self(keys.hash_one(session.inner()))
+    }
+    pub fn encode(&self) -> SecretBuf {
+        self.0.to_be_bytes()
+    }
+    /// The rest of `body` if it starts with the secret.
+    pub fn strip<'a>(&self, body: &'a [u8]) -> Option<&'a [u8]> {
+        let (secret, rest) = body.split_first_chunk::<SECRET_SIZE>()?;
+        (*secret == self.encode()).then_some(rest)
     }
 }
 
@@ -114,3 +489,74 @@ impl Session {
         self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_features() {
+        let unknown = Features::from_bits(1 << 31);
+        let cases = [
+            // Client, listener, settled on
+            (
+                Features::supported(),
+                Features::supported(),
+                Features::supported(),
+            ),
+            (
+                Features::PING.union(Features::CLOSE),
+                Features::CLOSE.union(Features::KEEPALIVE),
+                Features::CLOSE,
+            ),
+            (Features::PING, Features::KEEPALIVE, Features::empty()),
+            (Features::empty(), Features::supported(), Features::empty()),
+            (
+                Features::supported().union(unknown),
+                Features::supported(),
+                Features::supported(),
+            ),
+        ];
+        let session = Session::new(0x0123_4567_89ab_cdef);
+        let secret = Secret::derive(&RandomState::new(), session);
+        let conns = NonZeroUsize::new(3).unwrap();
+        for (client, listener, settled) in cases {
+            let init = Init::decode(Init::new(session, conns, client).encode()).unwrap();
+            assert_eq!(init.features(), client);
+            assert_eq!(init.session(), session);
+            assert_eq!(init.conns(), conns);
+            let features = init.features().intersection(listener);
+            assert_eq!(features, settled);
+            let ack = InitAck::decode(InitAck::new(session, features, secret).encode());
+            assert_eq!(ack.features(), settled);
+            assert_eq!(ack.session(), session);
+            assert_eq!(ack.secret(), secret);
+        }
+    }
+
+    #[test]
+    fn reject_other_versions() {
+        let init = Init::new(Session::new(1), NonZeroUsize::MIN, Features::supported());
+        let mut buf = init.encode();
+        buf[0] = VERSION + 1;
+        let err = Init::decode(buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn close_needs_secret() {
+        let keys = RandomState::new();
+        let session = Session::new(7);
+        let secret = Secret::derive(&keys, session);
+        assert_eq!(secret, Secret::derive(&keys, session));
+        let body = encode_close(secret, 42);
+        assert_eq!(decode_close(secret, &body), Some(42));
+        let other = Secret::derive(&RandomState::new(), session);
+        assert_eq!(decode_close(other, &body), None);
+        // The session number alone no longer closes anything
+        let mut forged = session.inner().to_be_bytes().to_vec();
+        forged.extend(42_u64.to_be_bytes());
+        assert_eq!(decode_close(secret, &forged), None);
+        assert_eq!(decode_close(secret, &body[..SECRET_SIZE]), None);
+    }
+}
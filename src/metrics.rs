@@ -0,0 +1,416 @@
+use std::{
+    fmt::Write as _,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use primitive::sync::mutex::SpinMutex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+};
+
+use crate::{
+    listen::ListenerCounters,
+    message::Session,
+    stats::{StatsSource, Totals},
+};
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// How long a scraper may take to send its request before the connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Name, help text and how to read the value of a metric family.
+type Family<T, V> = (&'static str, &'static str, fn(&T) -> V);
+
+/// Collects metrics of the listeners and connections configured with it and serves them in the Prometheus text format.
+///
+/// Sessions drop out of the registry once both halves of their connection are dropped, listeners once they are dropped.
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    inner: Arc<Inner>,
+}
+#[derive(Debug, Default)]
+struct Inner {
+    listeners: SpinMutex<Vec<ListenerEntry>>,
+    sessions: SpinMutex<Vec<SessionEntry>>,
+    connects: AtomicU64,
+    connect_failures: AtomicU64,
+}
+#[derive(Debug)]
+struct ListenerEntry {
+    addrs: String,
+    counters: Weak<ListenerCounters>,
+}
+#[derive(Debug)]
+struct SessionEntry {
+    session: Session,
+    side: &'static str,
+    source: Weak<StatsSource>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub(crate) fn register_listener(
+        &self,
+        addrs: impl Iterator<Item = SocketAddr>,
+        counters: &Arc<ListenerCounters>,
+    ) {
+        let addrs = addrs.map(|addr| addr.to_string()).collect::<Vec<_>>();
+        self.inner.listeners.lock().push(ListenerEntry {
+            addrs: addrs.join(","),
+            counters: Arc::downgrade(counters),
+        });
+    }
+    pub(crate) fn register_session(
+        &self,
+        session: Session,
+        side: &'static str,
+        source: &Arc<StatsSource>,
+    ) {
+        let mut sessions = self.inner.sessions.lock();
+        sessions.retain(|entry| entry.source.strong_count() != 0);
+        sessions.push(SessionEntry {
+            session,
+            side,
+            source: Arc::downgrade(source),
+        });
+    }
+    /// Counts a `connect` call, failed or not.
+    pub(crate) fn connect_result<T>(&self, res: &io::Result<T>) {
+        self.inner.connects.fetch_add(1, Ordering::Relaxed);
+        if res.is_err() {
+            self.inner.connect_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = Exposition::default();
+
+        let listeners = {
+            let mut listeners = self.inner.listeners.lock();
+            listeners.retain(|entry| entry.counters.strong_count() != 0);
+            listeners
+                .iter()
+                .filter_map(|entry| {
+                    Some((
+                        format!("listener=\"{}\"", entry.addrs),
+                        entry.counters.upgrade()?,
+                    ))
+                })
+                .collect::<Vec<_>>()
+        };
+        let listener_counters: [Family<ListenerCounters, u64>; 9] = [
+            (
+                "mpudp_listener_accepted_sessions_total",
+                "Sessions accepted, whether or not every path completed the handshake.",
+                |c| c.accepted.get(),
+            ),
+            (
+                "mpudp_listener_partially_accepted_sessions_total",
+                "Sessions accepted before all of their paths opened.",
+                |c| c.accepted_partially.get(),
+            ),
+            (
+                "mpudp_listener_rejected_malformed_total",
+                "Paths opened with something other than a well-formed Init or Data.",
+                |c| c.rejected_malformed.get(),
+            ),
+            (
+                "mpudp_listener_rejected_too_many_paths_total",
+                "Paths of sessions asking for more paths than allowed.",
+                |c| c.rejected_too_many_paths.get(),
+            ),
+            (
+                "mpudp_listener_rejected_backlog_full_total",
+                "Paths turned away because too many sessions were incomplete.",
+                |c| c.rejected_backlog_full.get(),
+            ),
+            (
+                "mpudp_listener_backlog_timeouts_total",
+                "Incomplete sessions dropped for not opening all their paths in time.",
+                |c| c.backlog_timeouts.get(),
+            ),
+            (
+                "mpudp_listener_joined_paths_total",
+                "Paths that joined a session after it was accepted.",
+                |c| c.joined.get(),
+            ),
+            (
+                "mpudp_listener_rejected_unknown_session_total",
+                "Paths asking to join a session the listener is not running or without its secret.",
+                |c| c.rejected_unknown_session.get(),
+            ),
+            (
+                "mpudp_listener_rebinds_total",
+                "Paths of running sessions heard from at a new client address.",
+                |c| c.rebinds.get(),
+            ),
+        ];
+        for (name, help, counter) in listener_counters {
+            out.family(name, "counter", help);
+            for (labels, counters) in &listeners {
+                out.sample(name, labels, counter(counters));
+            }
+        }
+        let name = "mpudp_listener_backlog_sessions";
+        out.family(
+            name,
+            "gauge",
+            "Sessions waiting for the rest of their paths.",
+        );
+        for (labels, counters) in &listeners {
+            out.sample(name, labels, counters.backlog_len.get());
+        }
+
+        let name = "mpudp_connects_total";
+        out.family(name, "counter", "Connections attempted.");
+        out.sample(name, "", self.inner.connects.load(Ordering::Relaxed));
+        let name = "mpudp_connect_failures_total";
+        out.family(
+            name,
+            "counter",
+            "Connections that failed to complete the handshake.",
+        );
+        out.sample(
+            name,
+            "",
+            self.inner.connect_failures.load(Ordering::Relaxed),
+        );
+
+        let sessions = {
+            let mut sessions = self.inner.sessions.lock();
+            sessions.retain(|entry| entry.source.strong_count() != 0);
+            sessions
+                .iter()
+                .filter_map(|entry| {
+                    let stats = entry.source.upgrade()?.snapshot();
+                    Some((entry.session, entry.side, stats))
+                })
+                .collect::<Vec<_>>()
+        };
+        let name = "mpudp_sessions";
+        out.family(name, "gauge", "Open sessions.");
+        for side in ["client", "server"] {
+            let n = sessions.iter().filter(|(_, s, _)| *s == side).count();
+            out.sample(name, &format!("side=\"{side}\""), n);
+        }
+
+        let paths = sessions
+            .iter()
+            .flat_map(|(session, side, stats)| {
+                stats.paths.iter().enumerate().map(move |(i, path)| {
+                    let labels = format!(
+                        "session=\"{:016x}\",side=\"{side}\",path=\"{i}\",local=\"{}\",peer=\"{}\"",
+                        session.inner(),
+                        path.local_addr,
+                        path.peer_addr,
+                    );
+                    (labels, path)
+                })
+            })
+            .collect::<Vec<_>>();
+        let path_counters: [Family<Totals, u64>; 6] = [
+            (
+                "mpudp_path_sent_packets_total",
+                "Packets sent on the path.",
+                |t| t.sent_packets,
+            ),
+            (
+                "mpudp_path_sent_bytes_total",
+                "Bytes sent on the path.",
+                |t| t.sent_bytes,
+            ),
+            (
+                "mpudp_path_recv_packets_total",
+                "Packets received on the path.",
+                |t| t.recv_packets,
+            ),
+            (
+                "mpudp_path_recv_bytes_total",
+                "Bytes received on the path.",
+                |t| t.recv_bytes,
+            ),
+            (
+                "mpudp_path_duplicates_total",
+                "Datagrams received on the path after a copy of them was delivered.",
+                |t| t.duplicates,
+            ),
+            (
+                "mpudp_path_bad_packets_total",
+                "Datagrams received on the path that could not be decoded.",
+                |t| t.bad_packets,
+            ),
+        ];
+        for (name, help, counter) in path_counters {
+            out.family(name, "counter", help);
+            for (labels, path) in &paths {
+                out.sample(name, labels, counter(&path.traffic));
+            }
+        }
+        let name = "mpudp_path_srtt_seconds";
+        out.family(name, "gauge", "Smoothed RTT of the path.");
+        for (labels, path) in &paths {
+            if let Some(srtt) = path.srtt {
+                out.sample(name, labels, srtt.as_secs_f64());
+            }
+        }
+        let name = "mpudp_path_loss_ratio";
+        out.family(
+            name,
+            "gauge",
+            "Share of probes on the path that went unanswered.",
+        );
+        for (labels, path) in &paths {
+            if let Some(loss) = path.loss {
+                out.sample(name, labels, loss);
+            }
+        }
+        let name = "mpudp_path_alive";
+        out.family(name, "gauge", "Whether the path is still considered alive.");
+        for (labels, path) in &paths {
+            out.sample(name, labels, u8::from(path.state.is_alive()));
+        }
+
+        out.text
+    }
+
+    /// Answers every HTTP request accepted on `listener` with [`Self::render`].
+    ///
+    /// Runs until accepting fails.
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        let mut scrapes = JoinSet::new();
+        loop {
+            while scrapes.try_join_next().is_some() {}
+            let (stream, _) = listener.accept().await?;
+            let registry = self.clone();
+            scrapes.spawn(async move {
+                let _ = registry.scrape(stream).await;
+            });
+        }
+    }
+    async fn scrape(&self, mut stream: TcpStream) -> io::Result<()> {
+        tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request head too slow"))??;
+        let body = self.render();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+async fn read_request_head(stream: &mut TcpStream) -> io::Result<()> {
+    let mut head = vec![];
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if MAX_REQUEST_HEAD < head.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct Exposition {
+    text: String,
+}
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.text, "# HELP {name} {help}").unwrap();
+        writeln!(self.text, "# TYPE {name} {kind}").unwrap();
+    }
+    fn sample(&mut self, name: &str, labels: &str, value: impl core::fmt::Display) {
+        if labels.is_empty() {
+            writeln!(self.text, "{name} {value}").unwrap();
+        } else {
+            writeln!(self.text, "{name}{{{labels}}} {value}").unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{MpUdpConfig, MpUdpListenerConfig},
+        conn::MpUdpConn,
+        listen::MpUdpListener,
+        schedule::PathRole,
+    };
+
+    #[tokio::test]
+    async fn scrape_over_loopback() {
+        let registry = Registry::new();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let config = MpUdpListenerConfig::builder()
+            .metrics(registry.clone())
+            .build()
+            .unwrap();
+        let addrs = std::iter::repeat_n((addr, PathRole::Primary), 2);
+        let mut listener = MpUdpListener::bind(addrs, config).await.unwrap();
+        let addrs = listener.local_addrs().collect::<Vec<_>>();
+        let config = MpUdpConfig::builder()
+            .metrics(registry.clone())
+            .build()
+            .unwrap();
+        let connect = MpUdpConn::connect(addrs.into_iter().map(|a| (a, PathRole::Primary)), config);
+        let (client, server) = tokio::join!(connect, listener.accept());
+        let (_client, _server) = (client.unwrap(), server.unwrap());
+
+        let http = TcpListener::bind(addr).await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        let serving = registry.clone();
+        tokio::spawn(async move { serving.serve(http).await });
+        let mut stream = TcpStream::connect(http_addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for family in [
+            "mpudp_listener_accepted_sessions_total{listener=",
+            "mpudp_listener_backlog_sessions{listener=",
+            "mpudp_connects_total 1",
+            "mpudp_sessions{side=\"client\"} 1",
+            "mpudp_sessions{side=\"server\"} 1",
+            "mpudp_path_sent_packets_total{session=",
+            "mpudp_path_alive{session=",
+        ] {
+            assert!(
+                response.contains(family),
+                "{family} missing from:\n{response}"
+            );
+        }
+        let paths = response
+            .lines()
+            .filter(|line| line.starts_with("mpudp_path_alive{"))
+            .count();
+        assert_eq!(paths, 4);
+    }
+}
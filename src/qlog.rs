@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, Weak, mpsc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use primitive::sync::mutex::SpinMutex;
+
+use crate::message::Session;
+
+const QLOG_VERSION: &str = "0.4";
+
+/// Writes the events of each session to its own newline-delimited JSON file in a directory, following the qlog main schema.
+///
+/// Files are named after the session number and the end that wrote them, e.g. `00ab…-client.qlog`.
+/// One thread writes all files of a sink; events it cannot keep up with are dropped rather than queued without bound.
+#[derive(Debug, Clone)]
+pub struct EventSink {
+    dir: PathBuf,
+    writers: Arc<SpinMutex<Writers>>,
+    tx: mpsc::SyncSender<Op>,
+    importance: Importance,
+}
+/// How much of what happens a session records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Importance {
+    /// Handshakes, path changes and weight updates.
+    #[default]
+    Base,
+    /// Also every scheduling decision, which costs a line per datagram sent.
+    Extra,
+}
+/// Files still open, by session and vantage point.
+type Writers = HashMap<FileKey, Weak<Writer>>;
+impl EventSink {
+    /// Creates `dir` if it does not exist yet and starts the thread that writes the files.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            writers: Default::default(),
+            tx: spawn_writer(),
+            importance: Importance::default(),
+        })
+    }
+    /// Records events up to `importance`, [`Importance::Base`] unless set.
+    pub fn importance(mut self, importance: Importance) -> Self {
+        self.importance = importance;
+        self
+    }
+    /// The file is opened on the writer thread, off the task that asks for it.
+    fn open(&self, session: Session, vantage_point: &'static str) -> Option<Arc<Writer>> {
+        let key = (session, vantage_point);
+        let mut writers = self.writers.lock();
+        if let Some(writer) = writers.get(&key).and_then(Weak::upgrade) {
+            return Some(writer);
+        }
+        writers.retain(|_, writer| writer.strong_count() != 0);
+        let path = self
+            .dir
+            .join(format!("{:016x}-{vantage_point}.qlog", session.inner()));
+        let alive = Arc::new(());
+        let open = Op::Open {
+            key,
+            path,
+            alive: Arc::downgrade(&alive),
+        };
+        self.tx.try_send(open).ok()?;
+        let writer = Arc::new(Writer {
+            key,
+            tx: self.tx.clone(),
+            _alive: alive,
+        });
+        writers.insert(key, Arc::downgrade(&writer));
+        Some(writer)
+    }
+}
+
+/// Where the events of one session go; does nothing if logging is off.
+#[derive(Debug, Clone)]
+pub(crate) struct EventLog {
+    writer: Option<Arc<Writer>>,
+    importance: Importance,
+}
+impl EventLog {
+    /// Shares the file with every other log of the same session and end that is still open.
+    pub fn open(sink: Option<&EventSink>, session: Session, vantage_point: &'static str) -> Self {
+        // Failing to log must not take the session down with it
+        let writer = sink.and_then(|sink| sink.open(session, vantage_point));
+        let importance = sink.map_or_else(Importance::default, |sink| sink.importance);
+        Self { writer, importance }
+    }
+    /// Records an event of [`Importance::Extra`], if the sink wants those.
+    pub fn emit_extra(&self, name: &str, data: impl FnOnce(&mut Fields)) {
+        if self.importance < Importance::Extra {
+            return;
+        }
+        self.emit(name, data);
+    }
+    pub fn emit(&self, name: &str, data: impl FnOnce(&mut Fields)) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let mut fields = Fields::default();
+        data(&mut fields);
+        // Absolute so that the time axis survives the file being reopened mid-handshake
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.;
+        writer.line(format!(
+            r#"{{"time":{time},"name":"mpudp:{name}","data":{{{}}}}}"#,
+            fields.0
+        ));
+    }
+}
+
+/// Members of the `data` object of an event.
+#[derive(Debug, Default)]
+pub(crate) struct Fields(String);
+impl Fields {
+    fn key(&mut self, key: &str) {
+        if !self.0.is_empty() {
+            self.0.push(',');
+        }
+        write!(self.0, r#""{key}":"#).unwrap();
+    }
+    pub fn num(&mut self, key: &str, value: impl Display) -> &mut Self {
+        self.key(key);
+        write!(self.0, "{value}").unwrap();
+        self
+    }
+    pub fn str(&mut self, key: &str, value: impl Display) -> &mut Self {
+        self.key(key);
+        self.0.push('"');
+        for c in value.to_string().chars() {
+            match c {
+                '"' => self.0.push_str(r#"\""#),
+                '\\' => self.0.push_str(r"\\"),
+                c if c.is_control() => write!(self.0, "\\u{:04x}", c as u32).unwrap(),
+                c => self.0.push(c),
+            }
+        }
+        self.0.push('"');
+        self
+    }
+    pub fn nums<T: Display>(
+        &mut self,
+        key: &str,
+        values: impl IntoIterator<Item = T>,
+    ) -> &mut Self {
+        self.key(key);
+        self.0.push('[');
+        for (i, value) in values.into_iter().enumerate() {
+            if i != 0 {
+                self.0.push(',');
+            }
+            write!(self.0, "{value}").unwrap();
+        }
+        self.0.push(']');
+        self
+    }
+}
+
+/// Lines queued for all files of a sink before new ones are dropped.
+const QUEUED_LINES: usize = 4096;
+/// How often the writer thread closes the files no session writes to anymore while nothing is logged.
+const CLOSE_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+type FileKey = (Session, &'static str);
+/// What the writer thread of a sink is asked to do.
+#[derive(Debug)]
+enum Op {
+    /// Opens the file of a session, writing the header if it is new, and keeps it open while `alive` is.
+    Open {
+        key: FileKey,
+        path: PathBuf,
+        alive: Weak<()>,
+    },
+    Line(FileKey, String),
+}
+
+/// Appends lines to the files of one sink on a thread shared by all of them, so that sessions never block on disk.
+fn spawn_writer() -> mpsc::SyncSender<Op> {
+    let (tx, rx) = mpsc::sync_channel::<Op>(QUEUED_LINES);
+    std::thread::spawn(move || {
+        let mut files = HashMap::<FileKey, (BufWriter<File>, Weak<()>)>::new();
+        loop {
+            let op = match rx.recv_timeout(CLOSE_IDLE_INTERVAL) {
+                Ok(op) => op,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    files.retain(|_, (_, alive)| alive.strong_count() != 0);
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            for op in std::iter::once(op).chain(rx.try_iter()) {
+                apply(&mut files, op);
+            }
+            // Keep the files readable while the sessions are still running
+            files.retain(|_, (file, alive)| file.flush().is_ok() && alive.strong_count() != 0);
+        }
+    });
+    tx
+}
+
+/// Failing to log only loses the events of the file concerned.
+fn apply(files: &mut HashMap<FileKey, (BufWriter<File>, Weak<()>)>, op: Op) {
+    match op {
+        Op::Open { key, path, alive } => {
+            if let Some((_, open)) = files.get_mut(&key) {
+                *open = alive;
+                return;
+            }
+            let Ok(file) = OpenOptions::new().create(true).append(true).open(path) else {
+                return;
+            };
+            let fresh = file.metadata().is_ok_and(|metadata| metadata.len() == 0);
+            let mut file = BufWriter::new(file);
+            let (session, vantage_point) = key;
+            if fresh
+                && writeln!(
+                    file,
+                    r#"{{"qlog_version":"{QLOG_VERSION}","qlog_format":"NDJSON","title":"mpudp","trace":{{"vantage_point":{{"type":"{vantage_point}"}},"common_fields":{{"group_id":"{:016x}","time_format":"absolute"}}}}}}"#,
+                    session.inner()
+                )
+                .is_err()
+            {
+                return;
+            }
+            files.insert(key, (file, alive));
+        }
+        Op::Line(key, line) => {
+            if let Some((file, _)) = files.get_mut(&key)
+                && writeln!(file, "{line}").is_err()
+            {
+                files.remove(&key);
+            }
+        }
+    }
+}
+
+/// The file of one session as seen from the session; the writer thread closes it once every clone is gone.
+#[derive(Debug)]
+struct Writer {
+    key: FileKey,
+    tx: mpsc::SyncSender<Op>,
+    _alive: Arc<()>,
+}
+impl Writer {
+    fn line(&self, line: String) {
+        let _ = self.tx.try_send(Op::Line(self.key, line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_sessions_from_one_thread() {
+        let dir = std::env::temp_dir().join(format!("mpudp-qlog-{:016x}", rand::random::<u64>()));
+        let sink = EventSink::new(&dir).unwrap();
+        let sessions = [Session::random(), Session::random()];
+        for session in sessions {
+            let log = EventLog::open(Some(&sink), session, "server");
+            log.emit("first", |f| {
+                f.num("n", 1);
+            });
+            // A second log of the same session and end appends to the same file
+            EventLog::open(Some(&sink), session, "server").emit("second", |_| ());
+            log.emit_extra("skipped", |_| ());
+        }
+        for session in sessions {
+            let path = dir.join(format!("{:016x}-server.qlog", session.inner()));
+            let mut lines = vec![];
+            for _ in 0..100 {
+                let content = fs::read_to_string(&path).unwrap_or_default();
+                lines = content.lines().map(str::to_owned).collect::<Vec<_>>();
+                if lines.len() == 3 {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            assert_eq!(lines.len(), 3, "{lines:?}");
+            assert!(lines[0].contains(r#""qlog_format":"NDJSON""#));
+            assert!(lines[1].contains(r#""name":"mpudp:first","data":{"n":1}"#));
+            assert!(lines[2].contains(r#""name":"mpudp:second""#));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,60 +1,171 @@
 use std::{
-    io::{self, Read},
     num::NonZeroUsize,
-    sync::Arc,
-    time::Instant,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
 };
 
 use bytes::BytesMut;
 use primitive::arena::obj_pool::{ArcObjPool, ObjScoped};
-use tokio::{net::UdpSocket, task::JoinSet};
+use tokio::{
+    net::UdpSocket,
+    sync::{Notify, broadcast, watch},
+    task::JoinSet,
+};
 use udp_listener::{ConnRead, Packet};
 
 use crate::{
-    message::{HEADER_SIZE, Header},
-    schedule::Stats,
+    clock::Clock,
+    config::{Liveness, SessionConfig},
+    dedup::{DupWindow, Verdict},
+    event::{Notifier, PathEvent},
+    message::{
+        Features, Kind, Side, decode_close, decode_data, decode_timestamp, encode_path_ids,
+        encode_timestamp,
+    },
+    qlog::EventLog,
+    reorder::Reorder,
+    schedule::{PathRole, PathState, Stat},
+    stats::{SessionStats, StatsSource},
+    write::UdpSender,
 };
 
 #[derive(Debug)]
 pub struct MpUdpRead {
     rx: tokio::sync::mpsc::Receiver<(usize, UdpRecvPkt)>,
     _recving: JoinSet<()>,
-    stats: Stats,
-    with_header: bool,
+    side: Side,
+    pong: Arc<Notify>,
+    notifier: Arc<Notifier>,
+    /// Set once the peer has closed the session and its data has drained, which ends every receiving task.
+    closed: Arc<watch::Sender<bool>>,
+    /// The sequence number the data of the peer ends before, once it has sent [`Kind::Close`].
+    closing: watch::Receiver<Option<u64>>,
+    joiner: PathJoiner,
+    dedup: DupWindow,
+    dedup_stats: DedupStats,
+    reorder: Option<Reorder<(UdpRecvPkt, usize)>>,
+    source: Arc<StatsSource>,
+    idle_timeout: Option<Duration>,
+    clock: Arc<dyn Clock>,
 }
 impl MpUdpRead {
-    pub(crate) fn new(conns: Vec<UdpRecver>, stats: Stats, with_header: bool) -> Self {
+    pub(crate) fn new(
+        conns: Vec<UdpRecver>,
+        source: Arc<StatsSource>,
+        side: Side,
+        features: Features,
+        config: &SessionConfig,
+        log: EventLog,
+    ) -> Self {
+        let (stats, _) = source.paths();
         assert_eq!(conns.len(), stats.len());
-        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let (tx, rx) = tokio::sync::mpsc::channel(config.read_queue().get());
+        let pong = Arc::new(Notify::new());
+        let notifier = Arc::new(Notifier::new(
+            stats.iter().map(|s| s.lock().state()).collect(),
+            config.rtt_change_threshold(),
+            log,
+        ));
+        let closed = Arc::new(watch::Sender::new(false));
+        let closing = Arc::new(watch::Sender::new(None));
         let mut recving = JoinSet::new();
-        for (i, mut conn) in conns.into_iter().enumerate() {
-            let tx = tx.clone();
+        recving.spawn(drain(
+            closing.subscribe(),
+            Arc::clone(&closed),
+            Arc::clone(config.clock()),
+            config.close_drain_timeout(),
+        ));
+        if features.contains(Features::PING) {
+            let mut closed = closed.subscribe();
+            let monitor = monitor(
+                Arc::clone(&source),
+                side,
+                Arc::clone(&notifier),
+                config.liveness(),
+                Arc::clone(config.clock()),
+            );
             recving.spawn(async move {
-                while let Some(pkt) = conn.recv().await {
-                    if tx.send((i, pkt)).await.is_err() {
-                        return;
-                    }
+                tokio::select! {
+                    () = monitor => (),
+                    _ = closed.wait_for(|closed| *closed) => (),
                 }
             });
         }
+        if let Some(interval) = config.keepalive_interval()
+            && features.contains(Features::KEEPALIVE)
+        {
+            let mut closed = closed.subscribe();
+            let keepalive = keepalive(Arc::clone(&source), side, interval);
+            recving.spawn(async move {
+                tokio::select! {
+                    () = keepalive => (),
+                    _ = closed.wait_for(|closed| *closed) => (),
+                }
+            });
+        }
+        let (joins_tx, joins_rx) = tokio::sync::mpsc::unbounded_channel();
+        let joiner = PathJoiner {
+            source: Arc::downgrade(&source),
+            notifier: Arc::downgrade(&notifier),
+            paths: joins_tx,
+            features,
+        };
+        let ctx = Arc::new(PathContext {
+            tx,
+            source: Arc::clone(&source),
+            side,
+            pong: Arc::clone(&pong),
+            notifier: Arc::clone(&notifier),
+            closed: Arc::clone(&closed),
+            closing: Arc::clone(&closing),
+            clock: Arc::clone(config.clock()),
+            liveness: config.liveness(),
+            features,
+        });
+        recving.spawn(attach(ctx, conns, joins_rx));
         Self {
             rx,
             _recving: recving,
-            stats,
-            with_header,
+            side,
+            pong,
+            notifier,
+            closed,
+            closing: closing.subscribe(),
+            joiner,
+            dedup: DupWindow::new(),
+            dedup_stats: DedupStats::default(),
+            reorder: match config.delivery() {
+                Delivery::Unordered => None,
+                Delivery::Ordered {
+                    buffer,
+                    gap_timeout,
+                } => Some(Reorder::new(0, buffer, gap_timeout)),
+            },
+            source,
+            idle_timeout: config.idle_timeout(),
+            clock: Arc::clone(config.clock()),
         }
     }
 
     pub fn try_recv(&mut self, buf: &mut [u8]) -> Result<Option<usize>, RecvError> {
         loop {
-            let (i, pkt) = match self.rx.try_recv() {
+            self.check_drained();
+            if let Some(n) = self.release(buf) {
+                return Ok(Some(n));
+            }
+            let pkt = match self.rx.try_recv() {
                 Ok(x) => x,
                 Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => {
-                    return Err(RecvError::Dead);
+                    return Err(self.end());
+                }
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                    if self.idle_for().is_some_and(|left| left.is_zero()) {
+                        return Err(RecvError::TimedOut);
+                    }
+                    return Ok(None);
                 }
-                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => return Ok(None),
             };
-            let Some(n) = self.copy(buf, i, pkt)? else {
+            let Some(n) = self.copy(buf, pkt)? else {
                 continue;
             };
             return Ok(Some(n));
@@ -62,62 +173,667 @@ impl MpUdpRead {
     }
     pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, RecvError> {
         loop {
-            let (i, pkt) = self.rx.recv().await.ok_or(RecvError::Dead)?;
-            let Some(n) = self.copy(buf, i, pkt)? else {
+            self.check_drained();
+            if let Some(n) = self.release(buf) {
+                return Ok(n);
+            }
+            let gap_deadline = self.reorder.as_ref().and_then(|r| r.deadline());
+            let idle_left = self.idle_for();
+            if idle_left.is_some_and(|left| left.is_zero()) {
+                return Err(RecvError::TimedOut);
+            }
+            let idle_deadline = idle_left.map(|left| self.clock.now() + left);
+            let next = tokio::select! {
+                next = self.rx.recv() => next,
+                () = wait_until(&*self.clock, gap_deadline) => continue,
+                // The peer may have sent everything already
+                Ok(()) = self.closing.changed() => continue,
+                // Paths may have heard from the peer in the meantime
+                () = wait_until(&*self.clock, idle_deadline) => continue,
+            };
+            let pkt = next.ok_or_else(|| self.end())?;
+            let Some(n) = self.copy(buf, pkt)? else {
                 continue;
             };
             return Ok(n);
         }
     }
-    fn copy(&self, buf: &mut [u8], i: usize, pkt: UdpRecvPkt) -> Result<Option<usize>, RecvError> {
-        let now = Instant::now();
-        self.stats[i].lock().recv(now);
-        let pkt = pkt.get();
-        let payload = if self.with_header {
-            let mut rdr = io::Cursor::new(pkt);
-            let mut header = [0; HEADER_SIZE];
-            if rdr.read_exact(&mut header).is_err() {
-                return Err(RecvError::BadPacket);
+    /// Liveness of each path, updated as paths fall silent, die and come back.
+    pub fn path_states(&self) -> watch::Receiver<Vec<PathState>> {
+        self.notifier.states()
+    }
+    /// Path changes as they happen, for reacting to paths degrading without polling.
+    ///
+    /// Receivers that fall behind skip the oldest events.
+    pub fn events(&self) -> broadcast::Receiver<PathEvent> {
+        self.notifier.events()
+    }
+    /// Hands paths opened after the handshake to the session.
+    pub(crate) fn joiner(&self) -> &PathJoiner {
+        &self.joiner
+    }
+    pub(crate) fn path_changed(&self, path: usize, change: Option<PathState>) {
+        self.notifier.state(path, change);
+    }
+    /// Woken whenever a path takes a new RTT sample.
+    pub(crate) fn pong(&self) -> &Notify {
+        &self.pong
+    }
+    /// Per-path counters and estimates of the session.
+    pub fn stats(&self) -> SessionStats {
+        self.source.snapshot()
+    }
+    /// Counters of the duplicate suppression applied to incoming datagrams.
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.dedup_stats
+    }
+
+    /// Time left until the session counts as idle, if it can time out at all.
+    fn idle_for(&self) -> Option<Duration> {
+        let timeout = self.idle_timeout?;
+        let now = self.clock.now();
+        let (stats, _) = self.source.paths();
+        let silence = stats.iter().map(|s| s.lock().silence(now)).min()?;
+        Some(timeout.saturating_sub(silence))
+    }
+    /// Stops receiving once every datagram the peer sent before closing has been seen.
+    fn check_drained(&self) {
+        let closing = *self.closing.borrow();
+        if closing.is_some_and(|end| self.dedup.seen_all_below(end)) {
+            self.closed.send_replace(true);
+        }
+    }
+    /// Why no datagram is left to receive.
+    fn end(&self) -> RecvError {
+        if *self.closed.borrow() {
+            return RecvError::Closed;
+        }
+        RecvError::Dead
+    }
+    /// Copies out the next datagram held back for reordering, if it is due.
+    fn release(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let reorder = self.reorder.as_mut()?;
+        let (pkt, offset) = reorder.pop(self.clock.now())?;
+        Some(copy_payload(buf, &pkt.get()[offset..]))
+    }
+    fn copy(
+        &mut self,
+        buf: &mut [u8],
+        (path, pkt): (usize, UdpRecvPkt),
+    ) -> Result<Option<usize>, RecvError> {
+        let now = self.clock.now();
+        let Ok((kind, payload)) = self.side.decode_frame(pkt.get()) else {
+            self.bad_packet(path, "undecodable frame");
+            return Err(RecvError::BadPacket);
+        };
+        match kind {
+            Kind::Data => (),
+            Kind::Init
+            | Kind::InitAck
+            | Kind::Ping
+            | Kind::Pong
+            | Kind::Close
+            | Kind::Keepalive
+            | Kind::Join
+            | Kind::Retire
+            | Kind::Challenge
+            | Kind::Response
+            | Kind::Established => return Ok(None),
+        }
+        let Ok((seq, payload)) = decode_data(payload) else {
+            self.bad_packet(path, "truncated data");
+            return Err(RecvError::BadPacket);
+        };
+        match self.dedup.insert(seq) {
+            Verdict::New => self.dedup_stats.delivered += 1,
+            Verdict::Duplicate => {
+                self.dedup_stats.duplicates += 1;
+                self.source.stat(path).lock().duplicate();
+                return Ok(None);
             }
-            let header = Header::decode(header).map_err(|_| RecvError::BadPacket)?;
-            if !header.with_payload() {
+            // Dropped rather than risking a second delivery
+            Verdict::TooOld => {
+                self.dedup_stats.too_old += 1;
                 return Ok(None);
             }
-            &pkt[HEADER_SIZE..]
-        } else {
-            pkt
+        }
+        let Some(reorder) = &mut self.reorder else {
+            return Ok(Some(copy_payload(buf, payload)));
         };
-        let copy_len = buf.len().min(payload.len());
-        buf[..copy_len].copy_from_slice(&payload[..copy_len]);
-        Ok(Some(copy_len))
+        let offset = pkt.get().len() - payload.len();
+        reorder.push(seq, (pkt, offset), now);
+        Ok(None)
+    }
+    fn bad_packet(&self, path: usize, reason: &str) {
+        self.source.stat(path).lock().bad_packet();
+        self.notifier.log().emit("bad_packet", |f| {
+            f.num("path", path).str("reason", reason);
+        });
     }
 }
+/// Runs the receiving task of every path, including those that join later, until none is left.
+///
+/// Validations of the new addresses of migrating paths run alongside and hand the connection to the path once they succeed.
+async fn attach(
+    ctx: Arc<PathContext>,
+    conns: Vec<UdpRecver>,
+    mut attaching: tokio::sync::mpsc::UnboundedReceiver<Attach>,
+) {
+    let mut tasks = JoinSet::new();
+    // Where each path takes the connection it migrates to
+    let mut migrations = vec![];
+    let spawn_path = |tasks: &mut JoinSet<_>, migrations: &mut Vec<_>, i: usize, conn| {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        if migrations.len() <= i {
+            migrations.resize_with(i + 1, || None);
+        }
+        migrations[i] = Some(tx);
+        tasks.spawn(recv_path(Arc::clone(&ctx), i, conn, rx));
+    };
+    for (i, conn) in conns.into_iter().enumerate() {
+        spawn_path(&mut tasks, &mut migrations, i, conn);
+    }
+    loop {
+        tokio::select! {
+            Some(attach) = attaching.recv() => match attach {
+                Attach::Join(i, conn) => spawn_path(&mut tasks, &mut migrations, i, conn),
+                Attach::Migrate(i, conn, sender) => {
+                    tasks.spawn(validate(Arc::clone(&ctx), i, conn, sender));
+                }
+            },
+            res = tasks.join_next() => {
+                let Some(res) = res else {
+                    return;
+                };
+                if let Ok(Some((i, conn))) = res
+                    && let Some(Some(migration)) = migrations.get(i)
+                {
+                    let _ = migration.send(conn);
+                }
+            }
+        }
+    }
+}
+/// Moves `path` over to `sender` once the client has answered a challenge sent there.
+///
+/// The challenge is repeated every probe interval and given up on after the dead-after interval of the liveness settings.
+/// Data arriving at the new address meanwhile is handed out as usual.
+async fn validate(
+    ctx: Arc<PathContext>,
+    path: usize,
+    mut conn: UdpRecver,
+    sender: UdpSender,
+) -> Option<(usize, UdpRecver)> {
+    let mut frame = vec![];
+    let deadline = tokio::time::Instant::now() + ctx.liveness.dead_after;
+    // The datagram that revealed the new address pays for the first challenge
+    if let Some(pkt) = conn.take_early() {
+        validating_recv(&ctx, path, &sender, pkt).await?;
+    }
+    while let Some(token) = sender.challenge() {
+        ctx.side.encode_frame(Kind::Challenge, &token, &mut frame);
+        // Sending more than the new address has paid for is left to the next round
+        let _ = sender.send(&mut frame).await;
+        let response = async {
+            while let Some(pkt) = conn.recv().await {
+                match validating_recv(&ctx, path, &sender, pkt).await {
+                    Some(true) => return true,
+                    Some(false) => (),
+                    None => return false,
+                }
+            }
+            false
+        };
+        let retry = tokio::time::Instant::now() + ctx.liveness.probe_interval;
+        match tokio::time::timeout_at(retry.min(deadline), response).await {
+            Ok(true) => break,
+            Ok(false) => return None,
+            Err(_) if deadline <= tokio::time::Instant::now() => {
+                ctx.notifier.log().emit("path_migration_failed", |f| {
+                    f.num("path", path).str("peer_addr", sender.peer_addr());
+                });
+                return None;
+            }
+            Err(_) => continue,
+        }
+    }
+    ctx.source.sender(path).migrate(&sender);
+    ctx.notifier.migrated(path, sender.peer_addr());
+    Some((path, conn))
+}
+/// Counts a datagram from the new address of `path` and hands it out if it is data.
+///
+/// Returns whether it answered the challenge, or `None` once the session is gone.
+async fn validating_recv(
+    ctx: &PathContext,
+    path: usize,
+    sender: &UdpSender,
+    pkt: UdpRecvPkt,
+) -> Option<bool> {
+    sender.received(pkt.get().len());
+    match ctx.side.decode_frame(pkt.get()) {
+        Ok((Kind::Response, body)) => return Some(answers(ctx.side, sender, body)),
+        Ok((Kind::Data, _)) => (),
+        _ => return Some(false),
+    }
+    // Only what is sent to the new address waits for it to be validated
+    ctx.tx.send((path, pkt)).await.ok()?;
+    Some(false)
+}
+/// Whether the body of a [`Kind::Response`] proves the sender is the client and at the address it came from.
+fn answers(side: Side, sender: &UdpSender, body: &[u8]) -> bool {
+    side.secret()
+        .strip(body)
+        .is_some_and(|token| sender.validate(token))
+}
+async fn recv_path(
+    ctx: Arc<PathContext>,
+    i: usize,
+    mut conn: UdpRecver,
+    mut migrations: tokio::sync::mpsc::UnboundedReceiver<UdpRecver>,
+) -> Option<(usize, UdpRecver)> {
+    let PathContext {
+        tx,
+        source,
+        side,
+        pong,
+        notifier,
+        closed,
+        closing,
+        clock,
+        liveness,
+        features,
+    } = &*ctx;
+    let stat = source.stat(i);
+    let sender = source.sender(i);
+    let mut frame = vec![];
+    let mut ended = closed.subscribe();
+    // When the client address was last challenged, while it is not validated
+    let mut challenged = None;
+    // When `Retire` is resent next and when to stop waiting for its echo, once the path is retired here
+    let mut retire_resend = None;
+    let mut retire_deadline = None;
+    loop {
+        if let Some(token) = sender.challenge()
+            && challenged.is_none_or(|at| {
+                liveness.probe_interval <= clock.now().saturating_duration_since(at)
+            })
+        {
+            side.encode_frame(Kind::Challenge, &token, &mut frame);
+            let _ = sender.send(&mut frame).await;
+            challenged = Some(clock.now());
+        }
+        if let Some(at) = retire_resend
+            && at <= clock.now()
+        {
+            if retire_deadline.is_some_and(|deadline| deadline <= at) {
+                notifier.log().emit("path_retire_unanswered", |f| {
+                    f.num("path", i);
+                });
+                break;
+            }
+            side.encode_frame(Kind::Retire, &side.secret().encode(), &mut frame);
+            let _ = sender.send(&mut frame).await;
+            retire_resend = Some(clock.now() + liveness.probe_interval);
+        }
+        let pkt = tokio::select! {
+            pkt = conn.recv() => pkt,
+            Some(migrated) = migrations.recv() => {
+                // The old connection goes away with the address the client left
+                conn = migrated;
+                continue;
+            }
+            _ = ended.wait_for(|closed| *closed) => None,
+            () = sender.retiring(), if retire_deadline.is_none() => {
+                let now = clock.now();
+                retire_resend = Some(now + liveness.probe_interval);
+                retire_deadline = Some(now + liveness.dead_after);
+                continue;
+            }
+            () = wait_until(&**clock, retire_resend) => continue,
+        };
+        // Ending the task drops the connection, which is what frees it in the listener
+        let Some(pkt) = pkt else {
+            break;
+        };
+        let now = clock.now();
+        let change = stat.lock().recv(now, pkt.get().len());
+        notifier.state(i, change);
+        sender.received(pkt.get().len());
+        match side.decode_frame(pkt.get()) {
+            Ok((kind @ (Kind::Init | Kind::Join), _)) => {
+                // A retransmitted `Init` or `Join` means the client has not seen our acknowledgement
+                if let Side::Server(ack) = side {
+                    notifier.log().emit("handshake_packet_received", |f| {
+                        f.str("kind", format_args!("{kind:?}")).num("path", i);
+                    });
+                    side.encode_frame(Kind::InitAck, &ack.encode(), &mut frame);
+                    let _ = sender.send(&mut frame).await;
+                    // A client still waiting on `Init` may have missed that the session was accepted without some paths
+                    if features.contains(Features::PARTIAL) {
+                        side.encode_frame(Kind::Established, &established(source), &mut frame);
+                        let _ = sender.send(&mut frame).await;
+                    }
+                    continue;
+                }
+            }
+            Ok((Kind::Keepalive | Kind::Established, _)) => continue,
+            Ok((Kind::Response, body)) => {
+                if answers(*side, &sender, body) {
+                    notifier.log().emit("path_validated", |f| {
+                        f.num("path", i).str("peer_addr", sender.peer_addr());
+                    });
+                }
+                continue;
+            }
+            Ok((Kind::Challenge, token)) => {
+                let body = [&side.secret().encode(), token].concat();
+                side.encode_frame(Kind::Response, &body, &mut frame);
+                let _ = sender.send(&mut frame).await;
+                continue;
+            }
+            Ok((Kind::Ping, body)) => {
+                side.encode_frame(Kind::Pong, body, &mut frame);
+                let _ = sender.send(&mut frame).await;
+                continue;
+            }
+            Ok((Kind::Pong, body)) => {
+                let Ok(sent) = decode_timestamp(body) else {
+                    continue;
+                };
+                sender.answered(sent);
+                if let Some(rtt) = now.checked_duration_since(sent) {
+                    source.record_rtt(rtt);
+                    let (change, srtt) = {
+                        let mut stat = stat.lock();
+                        let change = stat.rtt_sample(rtt);
+                        (change, stat.rtt().map(|rtt| rtt.smoothed()))
+                    };
+                    notifier.state(i, change);
+                    if let Some(srtt) = srtt {
+                        notifier.rtt(i, srtt);
+                    }
+                    pong.notify_waiters();
+                }
+                continue;
+            }
+            Ok((Kind::Close, body)) => {
+                // The same frame arrives on every path; the paths keep receiving until the data before it has drained
+                if let Some(end) = decode_close(side.secret(), body)
+                    && closing.send_if_modified(|closing| closing.replace(end).is_none())
+                {
+                    notifier.log().emit("connection_closed", |f| {
+                        f.str("owner", "remote").num("path", i).num("end", end);
+                    });
+                }
+                continue;
+            }
+            Ok((Kind::Retire, body)) => {
+                if side.secret().strip(body) != Some(&[]) {
+                    continue;
+                }
+                let change = stat.lock().retire();
+                // The end that retired the path first holds on to it until this echo arrives
+                if change.is_some() {
+                    side.encode_frame(Kind::Retire, body, &mut frame);
+                    let _ = sender.send(&mut frame).await;
+                }
+                notifier.state(i, change);
+                break;
+            }
+            _ => (),
+        }
+        if tx.send((i, pkt)).await.is_err() {
+            return None;
+        }
+    }
+    let change = stat.lock().kill();
+    notifier.state(i, change);
+    notifier.path_closed();
+    None
+}
+/// Body of a [`Kind::Established`] frame listing the paths of the session that are not retired.
+fn established(source: &StatsSource) -> Vec<u8> {
+    let (stats, senders) = source.paths();
+    let paths = senders
+        .iter()
+        .zip(stats)
+        .filter(|(_, stat)| stat.lock().state() != PathState::Retired)
+        .map(|(sender, _)| sender.path_id());
+    encode_path_ids(paths)
+}
+/// Probes paths that have gone quiet and declares them suspect, then dead, the longer they stay silent.
+async fn monitor(
+    source: Arc<StatsSource>,
+    side: Side,
+    notifier: Arc<Notifier>,
+    liveness: Liveness,
+    clock: Arc<dyn Clock>,
+) {
+    let mut frame = vec![];
+    let mut interval = tokio::time::interval(liveness.probe_interval);
+    loop {
+        interval.tick().await;
+        let now = clock.now();
+        let (stats, senders) = source.paths();
+        for (i, sender) in senders.iter().enumerate() {
+            let (change, silence, state) = {
+                let mut stat = stats[i].lock();
+                let change = stat.check_silence(now, liveness.suspect_after, liveness.dead_after);
+                (change, stat.silence(now), stat.state())
+            };
+            notifier.state(i, change);
+            if silence < liveness.probe_interval || state == PathState::Retired {
+                continue;
+            }
+            side.encode_frame(Kind::Ping, &encode_timestamp(now), &mut frame);
+            let _ = sender.send_ping(&mut frame, now).await;
+        }
+    }
+}
+/// Sends a keepalive frame on every path nothing went out on during the last interval.
+async fn keepalive(source: Arc<StatsSource>, side: Side, interval: Duration) {
+    let mut frame = vec![];
+    side.encode_frame(Kind::Keepalive, &[], &mut frame);
+    let mut sent = vec![];
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let (stats, senders) = source.paths();
+        for (i, sender) in senders.iter().enumerate() {
+            let Some(&last) = sent.get(i) else {
+                sent.push(sender.counters().0);
+                continue;
+            };
+            if sender.counters().0 == last && stats[i].lock().state() != PathState::Retired {
+                let _ = sender.send(&mut frame).await;
+            }
+            sent[i] = sender.counters().0;
+        }
+    }
+}
+/// Gives the datagrams the peer sent before closing `timeout` to arrive, then stops receiving.
+async fn drain(
+    mut closing: watch::Receiver<Option<u64>>,
+    closed: Arc<watch::Sender<bool>>,
+    clock: Arc<dyn Clock>,
+    timeout: Duration,
+) {
+    let mut ended = closed.subscribe();
+    tokio::select! {
+        Ok(_) = closing.wait_for(Option::is_some) => (),
+        _ = ended.wait_for(|closed| *closed) => return,
+    }
+    clock.sleep_until(clock.now() + timeout).await;
+    closed.send_replace(true);
+}
+/// Completes once `clock` reaches `deadline`, if there is one.
+async fn wait_until(clock: &dyn Clock, deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => clock.sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+fn copy_payload(buf: &mut [u8], payload: &[u8]) -> usize {
+    let copy_len = buf.len().min(payload.len());
+    buf[..copy_len].copy_from_slice(&payload[..copy_len]);
+    copy_len
+}
+
+/// How [`MpUdpRead`] hands out datagrams relative to the order they were sent in.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Delivery {
+    /// As soon as they arrive on any path.
+    #[default]
+    Unordered,
+    /// In sequence order.
+    ///
+    /// Up to `buffer` datagrams are held back waiting for a missing one; the gap is skipped once the buffer overflows or nothing has filled it for `gap_timeout`.
+    Ordered {
+        buffer: NonZeroUsize,
+        gap_timeout: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DedupStats {
+    pub delivered: u64,
+    pub duplicates: u64,
+    /// Datagrams that arrived too late to be checked against the duplicate window.
+    pub too_old: u64,
+}
+
 #[derive(Debug, Clone)]
 pub enum RecvError {
+    /// Every path is gone.
     Dead,
+    /// The peer closed the session; everything it sent before is delivered first, unless it has not arrived within the close drain timeout.
+    Closed,
+    /// Nothing arrived on any path for the idle timeout.
+    TimedOut,
     BadPacket,
 }
 
+/// What the receiving tasks of the paths of a session share.
+#[derive(Debug)]
+struct PathContext {
+    tx: tokio::sync::mpsc::Sender<(usize, UdpRecvPkt)>,
+    source: Arc<StatsSource>,
+    side: Side,
+    pong: Arc<Notify>,
+    notifier: Arc<Notifier>,
+    closed: Arc<watch::Sender<bool>>,
+    closing: Arc<watch::Sender<Option<u64>>>,
+    clock: Arc<dyn Clock>,
+    liveness: Liveness,
+    features: Features,
+}
+
+/// Connections handed to a running session.
+#[derive(Debug)]
+enum Attach {
+    /// A new path.
+    Join(usize, UdpRecver),
+    /// An existing path heard from at another address, not yet validated.
+    Migrate(usize, UdpRecver, UdpSender),
+}
+
+/// Attaches paths opened after the handshake to a running session.
+#[derive(Debug, Clone)]
+pub(crate) struct PathJoiner {
+    source: Weak<StatsSource>,
+    notifier: Weak<Notifier>,
+    paths: tokio::sync::mpsc::UnboundedSender<Attach>,
+    features: Features,
+}
+impl PathJoiner {
+    /// Returns the index of the new path, or `None` if the session is gone.
+    pub fn join(
+        &self,
+        conn: UdpRecver,
+        sender: Arc<UdpSender>,
+        role: PathRole,
+        now: Instant,
+    ) -> Option<usize> {
+        if self.is_closed() {
+            return None;
+        }
+        let source = self.source.upgrade()?;
+        let notifier = self.notifier.upgrade()?;
+        let stat = Stat::new(role, now);
+        let state = stat.state();
+        let path = source.add_path(stat, sender);
+        // Tracked before the index is handed out, so that the state of the path may change right away
+        notifier.added(path, state);
+        self.paths.send(Attach::Join(path, conn)).ok()?;
+        Some(path)
+    }
+    /// Moves the path the client knows as `path_id` to the address `sender` reaches once the client proves it is there.
+    ///
+    /// Returns the index of the path, or `None` if the session is gone or has no such path.
+    pub fn migrate(&self, path_id: u32, conn: UdpRecver, sender: UdpSender) -> Option<usize> {
+        let source = self.source.upgrade()?;
+        let (_, senders) = source.paths();
+        let path = senders.iter().position(|s| s.path_id() == path_id)?;
+        self.paths.send(Attach::Migrate(path, conn, sender)).ok()?;
+        Some(path)
+    }
+    /// What the session runs with, as settled on in the handshake.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+    /// Paths of the session that are not retired.
+    pub fn live_paths(&self) -> usize {
+        let Some(source) = self.source.upgrade() else {
+            return 0;
+        };
+        let (stats, _) = source.paths();
+        stats
+            .iter()
+            .filter(|stat| stat.lock().state() != PathState::Retired)
+            .count()
+    }
+    /// Whether every path of the session has stopped receiving, after which none can join.
+    pub fn is_closed(&self) -> bool {
+        self.paths.is_closed()
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum UdpRecver {
-    Server(ConnRead<Packet>),
+    /// Carries the packet that opened the path until it is handed out.
+    Server(ConnRead<Packet>, Option<Packet>),
     Client(Arc<UdpSocket>, ArcObjPool<BytesMut>),
 }
 impl UdpRecver {
-    pub fn from_client(socket: Arc<UdpSocket>) -> Self {
-        pub const PACKET_BUFFER_LENGTH: usize = 2_usize.pow(16);
-        const OBJ_POOL_SHARDS: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(4) };
+    pub fn from_server(conn_read: ConnRead<Packet>, early: Option<Packet>) -> Self {
+        Self::Server(conn_read, early)
+    }
+    pub fn from_client(socket: Arc<UdpSocket>, packet_buffer_len: NonZeroUsize) -> Self {
+        const OBJ_POOL_SHARDS: NonZeroUsize = NonZeroUsize::new(4).unwrap();
         let pool = ArcObjPool::new(
             None,
             OBJ_POOL_SHARDS,
-            || BytesMut::with_capacity(PACKET_BUFFER_LENGTH),
+            move || BytesMut::with_capacity(packet_buffer_len.get()),
             |buf| buf.clear(),
         );
         Self::Client(socket, pool)
     }
+    /// The packet that opened the path, unless it was handed out already.
+    pub fn take_early(&mut self) -> Option<UdpRecvPkt> {
+        match self {
+            UdpRecver::Server(_, early) => early.take().map(UdpRecvPkt::Server),
+            UdpRecver::Client(..) => None,
+        }
+    }
     pub async fn recv(&mut self) -> Option<UdpRecvPkt> {
+        if let Some(pkt) = self.take_early() {
+            return Some(pkt);
+        }
         match self {
-            UdpRecver::Server(conn_read) => conn_read.recv().recv().await.map(UdpRecvPkt::Server),
+            UdpRecver::Server(conn_read, _) => {
+                conn_read.recv().recv().await.map(UdpRecvPkt::Server)
+            }
             UdpRecver::Client(socket, pool) => {
                 let mut buf = pool.take_scoped();
                 if socket.recv_buf(&mut *buf).await.is_err() {
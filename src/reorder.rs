@@ -0,0 +1,156 @@
+use std::{
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+/// Holds back datagrams until every lower sequence number has been released or given up on.
+#[derive(Debug)]
+pub struct Reorder<T> {
+    next: u64,
+    pending: BTreeMap<u64, T>,
+    capacity: NonZeroUsize,
+    gap_timeout: Duration,
+    waiting_since: Option<Instant>,
+}
+impl<T> Reorder<T> {
+    pub fn new(next: u64, capacity: NonZeroUsize, gap_timeout: Duration) -> Self {
+        Self {
+            next,
+            pending: BTreeMap::new(),
+            capacity,
+            gap_timeout,
+            waiting_since: None,
+        }
+    }
+    /// Drops `value` if `seq` has already been released or skipped.
+    pub fn push(&mut self, seq: u64, value: T, now: Instant) {
+        if seq < self.next {
+            return;
+        }
+        self.pending.insert(seq, value);
+        if self.waiting_since.is_none() {
+            self.waiting_since = Some(now);
+        }
+    }
+    pub fn pop(&mut self, now: Instant) -> Option<T> {
+        let (&seq, _) = self.pending.first_key_value()?;
+        if seq != self.next {
+            let overflow = self.capacity.get() < self.pending.len();
+            let timed_out = self
+                .waiting_since
+                .is_some_and(|since| self.gap_timeout <= now.duration_since(since));
+            if !overflow && !timed_out {
+                return None;
+            }
+        }
+        let (seq, value) = self.pending.pop_first().unwrap();
+        self.next = seq + 1;
+        self.waiting_since = match self.pending.is_empty() {
+            true => None,
+            false => Some(now),
+        };
+        Some(value)
+    }
+    /// When the gap in front of the held-back datagrams will be skipped.
+    pub fn deadline(&self) -> Option<Instant> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        self.waiting_since.map(|since| since + self.gap_timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Step {
+        /// Pushes a sequence number at the given millisecond.
+        Push(u64, u64),
+        /// Pops at the given millisecond, expecting the sequence number.
+        Pop(u64, Option<u64>),
+        /// Expects the deadline at the given millisecond.
+        Deadline(Option<u64>),
+    }
+
+    #[test]
+    fn steps() {
+        use Step::*;
+        const GAP_TIMEOUT: u64 = 100;
+        const CAPACITY: usize = 3;
+        let cases: &[&[Step]] = &[
+            // In order
+            &[
+                Push(0, 0),
+                Pop(0, Some(0)),
+                Push(1, 0),
+                Pop(0, Some(1)),
+                Pop(0, None),
+            ],
+            // Held back until the gap fills
+            &[
+                Push(1, 0),
+                Pop(0, None),
+                Deadline(Some(GAP_TIMEOUT)),
+                Push(0, 10),
+                Pop(10, Some(0)),
+                Pop(10, Some(1)),
+                Pop(10, None),
+                Deadline(None),
+            ],
+            // The gap is skipped once it times out, and what it was waiting for is dropped
+            &[
+                Push(2, 0),
+                Pop(GAP_TIMEOUT - 1, None),
+                Pop(GAP_TIMEOUT, Some(2)),
+                Push(0, GAP_TIMEOUT),
+                Push(1, GAP_TIMEOUT),
+                Pop(GAP_TIMEOUT, None),
+                Deadline(None),
+            ],
+            // The gap is skipped once the buffer overflows
+            &[
+                Push(1, 0),
+                Push(2, 0),
+                Push(3, 0),
+                Pop(0, None),
+                Push(4, 0),
+                Pop(0, Some(1)),
+                Pop(0, Some(2)),
+                Pop(0, Some(3)),
+                Pop(0, Some(4)),
+            ],
+            // Every skip restarts the wait for the next gap
+            &[
+                Push(1, 0),
+                Push(3, 50),
+                Pop(GAP_TIMEOUT, Some(1)),
+                Pop(GAP_TIMEOUT, None),
+                Deadline(Some(2 * GAP_TIMEOUT)),
+                Pop(2 * GAP_TIMEOUT, Some(3)),
+            ],
+        ];
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        for (i, case) in cases.iter().enumerate() {
+            let mut reorder = Reorder::new(
+                0,
+                NonZeroUsize::new(CAPACITY).unwrap(),
+                Duration::from_millis(GAP_TIMEOUT),
+            );
+            for (j, &step) in case.iter().enumerate() {
+                match step {
+                    Push(seq, ms) => reorder.push(seq, seq, at(ms)),
+                    Pop(ms, expected) => {
+                        assert_eq!(reorder.pop(at(ms)), expected, "case {i}, step {j}")
+                    }
+                    Deadline(expected) => {
+                        assert_eq!(reorder.deadline(), expected.map(at), "case {i}, step {j}")
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,23 +1,72 @@
 use std::{
+    num::NonZeroUsize,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use primitive::sync::mutex::SpinMutex;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+/// Generator the weighted schedulers draw from, re-exported so that seeding one needs no extra dependency.
+pub use rand_chacha::ChaCha8Rng;
 
-const EPSILON_LATENCY: Duration = Duration::from_millis(1);
-const EXPLORE_PROB: f64 = 0.3;
+/// Decides which paths each outgoing datagram is sent on.
+pub trait Scheduler: core::fmt::Debug + Send {
+    /// Fills the empty `out` with the paths to send the next datagram on.
+    ///
+    /// `stats` holds one entry per path; it grows as paths join the session.
+    fn schedule(&mut self, stats: &[Stat], now: Instant, out: &mut Plan);
+    /// Probability of `path` being the primary choice, for policies that pick paths at random.
+    fn weight(&self, path: usize) -> Option<f64> {
+        let _ = path;
+        None
+    }
+}
+
+/// Paths chosen for the next datagram, by index.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    /// The first path is the primary choice; any further ones receive a copy of the same datagram.
+    pub send: Vec<usize>,
+    /// Paths that only get a small probe measuring their RTT, if the peer answers pings; otherwise they receive a copy too.
+    pub probe: Vec<usize>,
+}
+impl Plan {
+    pub fn clear(&mut self) {
+        self.send.clear();
+        self.probe.clear();
+    }
+}
 
-pub type Stats = Arc<[SpinMutex<Stat>]>;
-pub fn new_stats(conns: usize) -> Stats {
-    let now = Instant::now();
+/// Builds the [`Scheduler`] of a session from its number of paths.
+#[derive(Clone)]
+pub struct NewScheduler(Arc<dyn Fn(usize) -> Box<dyn Scheduler> + Send + Sync>);
+impl NewScheduler {
+    pub fn new(f: impl Fn(usize) -> Box<dyn Scheduler> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+    pub fn build(&self, paths: usize) -> Box<dyn Scheduler> {
+        (self.0)(paths)
+    }
+}
+impl Default for NewScheduler {
+    fn default() -> Self {
+        Self::new(|paths| Box::new(Weighted::new(paths)))
+    }
+}
+impl core::fmt::Debug for NewScheduler {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("NewScheduler").finish_non_exhaustive()
+    }
+}
+
+pub(crate) type Stats = Vec<Arc<SpinMutex<Stat>>>;
+pub(crate) fn new_stats(roles: impl Iterator<Item = PathRole>, now: Instant) -> Stats {
     let mut stats = vec![];
-    for _ in 0..conns {
-        let stat = Stat::new(now);
-        stats.push(SpinMutex::new(stat));
+    for role in roles {
+        let stat = Stat::new(role, now);
+        stats.push(Arc::new(SpinMutex::new(stat)));
     }
-    stats.into()
+    stats
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -25,85 +74,792 @@ pub struct Stat {
     last_sent_start: Instant,
     last_recv: Instant,
     prev_latency: Duration,
+    rtt: Option<Rtt>,
+    rtt_samples: u64,
+    state: PathState,
+    role: PathRole,
+    recv_packets: u64,
+    recv_bytes: u64,
+    duplicates: u64,
+    bad_packets: u64,
+    weight: Option<f64>,
 }
 impl Stat {
-    pub fn new(now: Instant) -> Self {
+    pub(crate) fn new(role: PathRole, now: Instant) -> Self {
         Self {
             last_sent_start: now,
             last_recv: now,
             prev_latency: Duration::ZERO,
+            rtt: None,
+            rtt_samples: 0,
+            state: PathState::Active,
+            role,
+            recv_packets: 0,
+            recv_bytes: 0,
+            duplicates: 0,
+            bad_packets: 0,
+            weight: None,
         }
     }
+    /// The smoothed RTT once the path has answered a probe; until then, a guess from the gap between sending and receiving on it.
     pub fn latency(&self, now: Instant) -> Duration {
+        if let Some(rtt) = &self.rtt {
+            return rtt.smoothed;
+        }
         if self.last_sent_start < self.last_recv {
             self.last_recv - self.last_sent_start
         } else {
             self.prev_latency.max(now - self.last_sent_start)
         }
     }
-    pub fn sent(&mut self, now: Instant) {
+    pub fn rtt(&self) -> Option<&Rtt> {
+        self.rtt.as_ref()
+    }
+    pub(crate) fn rtt_samples(&self) -> u64 {
+        self.rtt_samples
+    }
+    pub fn state(&self) -> PathState {
+        self.state
+    }
+    pub fn role(&self) -> PathRole {
+        self.role
+    }
+    pub fn recv_packets(&self) -> u64 {
+        self.recv_packets
+    }
+    pub fn recv_bytes(&self) -> u64 {
+        self.recv_bytes
+    }
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+    pub fn bad_packets(&self) -> u64 {
+        self.bad_packets
+    }
+    /// As last reported by the scheduler.
+    pub fn weight(&self) -> Option<f64> {
+        self.weight
+    }
+    /// How long nothing has arrived on the path.
+    pub fn silence(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_recv)
+    }
+    pub(crate) fn sent(&mut self, now: Instant) {
         if self.last_recv < self.last_sent_start {
             return;
         }
         self.prev_latency = self.last_recv - self.last_sent_start;
         self.last_sent_start = now;
     }
-    pub fn recv(&mut self, now: Instant) {
+    /// Returns the new state if receiving changed it.
+    pub(crate) fn recv(&mut self, now: Instant, bytes: usize) -> Option<PathState> {
         self.last_recv = now;
+        self.recv_packets += 1;
+        self.recv_bytes += bytes as u64;
+        let next = match self.state {
+            PathState::Suspect => PathState::Active,
+            PathState::Dead => PathState::Revived,
+            state => state,
+        };
+        self.set_state(next)
+    }
+    pub(crate) fn duplicate(&mut self) {
+        self.duplicates += 1;
+    }
+    pub(crate) fn bad_packet(&mut self) {
+        self.bad_packets += 1;
+    }
+    pub(crate) fn set_weight(&mut self, weight: Option<f64>) {
+        self.weight = weight;
+    }
+    /// Returns the new state if the answered probe changed it.
+    pub(crate) fn rtt_sample(&mut self, sample: Duration) -> Option<PathState> {
+        self.rtt_samples += 1;
+        match &mut self.rtt {
+            Some(rtt) => rtt.update(sample),
+            None => self.rtt = Some(Rtt::new(sample)),
+        }
+        match self.state {
+            PathState::Revived => self.set_state(PathState::Active),
+            _ => None,
+        }
+    }
+    /// Returns the new state if the path has been silent for too long.
+    pub(crate) fn check_silence(
+        &mut self,
+        now: Instant,
+        suspect_after: Duration,
+        dead_after: Duration,
+    ) -> Option<PathState> {
+        let silence = self.silence(now);
+        let next = match self.state {
+            PathState::Active | PathState::Revived if suspect_after <= silence => {
+                PathState::Suspect
+            }
+            PathState::Suspect if dead_after <= silence => PathState::Dead,
+            state => state,
+        };
+        self.set_state(next)
+    }
+    /// Returns the new state if the path was neither dead nor retired already.
+    pub(crate) fn kill(&mut self) -> Option<PathState> {
+        if self.state == PathState::Retired {
+            return None;
+        }
+        self.set_state(PathState::Dead)
+    }
+    /// Returns the new state if the path was not already retired.
+    pub(crate) fn retire(&mut self) -> Option<PathState> {
+        self.set_state(PathState::Retired)
+    }
+    fn set_state(&mut self, next: PathState) -> Option<PathState> {
+        if self.state == next {
+            return None;
+        }
+        self.state = next;
+        Some(next)
     }
 }
 
+/// Liveness of a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathState {
+    /// Delivering traffic.
+    Active,
+    /// Silent for a while but not given up on.
+    Suspect,
+    /// Silent for too long or closed locally; no longer scheduled.
+    Dead,
+    /// Delivering traffic again after being dead; active once it answers a probe.
+    Revived,
+    /// Taken out of the session by either end; never scheduled again.
+    Retired,
+}
+impl PathState {
+    pub fn is_alive(&self) -> bool {
+        !matches!(self, PathState::Dead | PathState::Retired)
+    }
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, PathState::Active | PathState::Revived)
+    }
+}
+
+/// Whether a path carries traffic in normal operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathRole {
+    #[default]
+    Primary,
+    /// Only used while no primary path is healthy, e.g. a metered link.
+    Backup,
+}
+
+/// Indices of the paths worth sending on.
+///
+/// That is the first non-empty group of: healthy primary paths, healthy backup paths, live primary paths, live backup paths, and finally every path not retired.
+pub fn usable_paths(stats: &[Stat]) -> impl Iterator<Item = usize> + '_ {
+    let tiers: [fn(&Stat) -> bool; 5] = [
+        |s| s.role() == PathRole::Primary && s.state().is_healthy(),
+        |s| s.role() == PathRole::Backup && s.state().is_healthy(),
+        |s| s.role() == PathRole::Primary && s.state().is_alive(),
+        |s| s.role() == PathRole::Backup && s.state().is_alive(),
+        |s| s.state() != PathState::Retired,
+    ];
+    let tier = tiers
+        .into_iter()
+        .find(|tier| stats.iter().any(tier))
+        .unwrap_or(|_| false);
+    (0..stats.len()).filter(move |&i| tier(&stats[i]))
+}
+
+/// RTT estimate of a path following RFC 6298.
+#[derive(Debug, Clone, Copy)]
+pub struct Rtt {
+    latest: Duration,
+    smoothed: Duration,
+    var: Duration,
+}
+impl Rtt {
+    fn new(sample: Duration) -> Self {
+        Self {
+            latest: sample,
+            smoothed: sample,
+            var: sample / 2,
+        }
+    }
+    fn update(&mut self, sample: Duration) {
+        self.latest = sample;
+        self.var = (self.var * 3 + self.smoothed.abs_diff(sample)) / 4;
+        self.smoothed = (self.smoothed * 7 + sample) / 8;
+    }
+    pub fn latest(&self) -> Duration {
+        self.latest
+    }
+    pub fn smoothed(&self) -> Duration {
+        self.smoothed
+    }
+    pub fn var(&self) -> Duration {
+        self.var
+    }
+}
+
+/// Picks paths at random weighted by their latency relative to the others, and explores another path while it might turn out faster than the fastest one known.
+///
+/// Exploration follows a discounted upper confidence bound: each path is credited with how often it was sent on, and the credit fades with the memory half-life of [`WeightedConfig`].
+/// The less credit a path has and the more its RTT varies, the wider the bound on its latency; once the optimistic end of the bound falls short of the fastest path by the explore margin, the path is no longer explored.
+/// Paths whose estimates are confident are thus all but never explored, while quiet and erratic ones are explored until they are known again.
 #[derive(Debug, Clone)]
-pub struct Rank {
+pub struct Weighted {
+    config: WeightedConfig,
     values: Vec<f64>,
+    usable: Vec<bool>,
+    /// How often each path was sent on, discounted by age.
+    pulls: Vec<f64>,
+    last_discount: Option<Instant>,
+    last_update: Option<Instant>,
+    rng: ChaCha8Rng,
 }
-impl Rank {
+impl Weighted {
     pub fn new(values: usize) -> Self {
+        Self::with_config(values, WeightedConfig::default()).unwrap()
+    }
+    /// Returns `None` if `config.memory` is zero, `config.temperature` is not positive and finite, or `config.confidence` or `config.explore_margin` is negative or not finite.
+    pub fn with_config(values: usize, config: WeightedConfig) -> Option<Self> {
+        if config.memory.is_zero() {
+            return None;
+        }
+        if !config.temperature.is_finite() || config.temperature <= 0. {
+            return None;
+        }
+        let non_negative = |x: f64| x.is_finite() && 0. <= x;
+        if !non_negative(config.confidence) || !non_negative(config.explore_margin) {
+            return None;
+        }
+        let usable = vec![true; values];
+        let pulls = vec![0.; values];
         let values = (0..values).map(|_| 1. / values as f64).collect();
-        Self { values }
+        Some(Self {
+            config,
+            values,
+            usable,
+            pulls,
+            last_discount: None,
+            last_update: None,
+            rng: ChaCha8Rng::from_entropy(),
+        })
     }
-    pub fn update_rank<'a>(&mut self, stats: impl Iterator<Item = &'a Stat> + Clone, now: Instant) {
-        rank(stats, &mut self.values, now);
+    /// Draws from `rng` instead of OS entropy; ChaCha8 is portable, so a seed replays the same choices on any platform.
+    pub fn with_rng(mut self, rng: ChaCha8Rng) -> Self {
+        self.rng = rng;
+        self
     }
-    pub fn choose_exploit(&self) -> Option<usize> {
-        if self.values.is_empty() {
-            return None;
+    fn update_rank(&mut self, stats: &[Stat], now: Instant) {
+        self.usable.clear();
+        self.usable.resize(stats.len(), false);
+        for i in usable_paths(stats) {
+            self.usable[i] = true;
         }
-        let mut rng = rand::thread_rng();
-        let mut remaining = rng.gen_range(0. ..1.);
+        rank(
+            stats,
+            &self.usable,
+            &mut self.values,
+            self.config.epsilon_latency,
+            self.config.temperature,
+            now,
+        );
+    }
+    fn choose_exploit(&mut self) -> Option<usize> {
+        let last = self.usable.iter().rposition(|&usable| usable)?;
+        let mut remaining = self.rng.gen_range(0. ..1.);
         for (i, &value) in self.values.iter().enumerate() {
             if remaining < value {
                 return Some(i);
             }
             remaining -= value;
         }
-        Some(self.values.len() - 1)
+        // Only reached through rounding errors in the weights
+        Some(last)
+    }
+    /// Fades the credit of every path by the time passed since the last call.
+    fn discount(&mut self, paths: usize, now: Instant) {
+        self.pulls.resize(paths, 0.);
+        if let Some(last) = self.last_discount {
+            let age = now.saturating_duration_since(last).as_secs_f64();
+            let factor = 0.5_f64.powf(age / self.config.memory.as_secs_f64());
+            self.pulls.iter_mut().for_each(|pulls| *pulls *= factor);
+        }
+        self.last_discount = Some(now);
     }
-    pub fn choose_explore(&self, except: usize) -> Option<usize> {
+    /// The path with the most optimistic latency relative to the fastest one, if that beats the fastest one by the explore margin.
+    ///
+    /// The fastest path is the yardstick and left to being exploited.
+    fn choose_explore(&self, except: usize, stats: &[Stat], now: Instant) -> Option<usize> {
         assert!(except < self.values.len());
-        if self.values.len() == 1 {
+        let latency = |i: usize| {
+            stats[i]
+                .latency(now)
+                .max(self.config.epsilon_latency)
+                .as_secs_f64()
+        };
+        let candidates = || (0..self.usable.len()).filter(|&i| self.usable[i]);
+        let fastest = candidates().min_by(|&a, &b| latency(a).total_cmp(&latency(b)))?;
+        let min_latency = latency(fastest);
+        let total: f64 = self.pulls.iter().sum();
+        let mut best = None;
+        let mut best_optimism = 1. - self.config.explore_margin;
+        for i in candidates().filter(|&i| i != except && i != fastest) {
+            let bonus = self.config.confidence
+                * (1. + uncertainty(&stats[i]))
+                * ((1. + total).ln() / self.pulls[i]).sqrt();
+            let optimism = latency(i) / min_latency - bonus;
+            if optimism < best_optimism {
+                best = Some(i);
+                best_optimism = optimism;
+            }
+        }
+        best
+    }
+}
+impl Scheduler for Weighted {
+    fn schedule(&mut self, stats: &[Stat], now: Instant, out: &mut Plan) {
+        let due = self.last_update.is_none_or(|last_update| {
+            self.config.rank_update_cool_down < now.saturating_duration_since(last_update)
+        });
+        let usable_changed = self.usable.len() != stats.len()
+            || usable_paths(stats).ne((0..self.usable.len()).filter(|&i| self.usable[i]));
+        if due || usable_changed {
+            self.update_rank(stats, now);
+            self.last_update = Some(now);
+        }
+        self.discount(stats.len(), now);
+        let Some(exploit) = self.choose_exploit() else {
+            return;
+        };
+        out.send.push(exploit);
+        self.pulls[exploit] += 1.;
+        if let Some(explore) = self.choose_explore(exploit, stats, now) {
+            if self.config.probe {
+                out.probe.push(explore);
+            } else {
+                out.send.push(explore);
+            }
+            self.pulls[explore] += 1.;
+        }
+    }
+    fn weight(&self, path: usize) -> Option<f64> {
+        self.values.get(path).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WeightedConfig {
+    /// Half-life of the credit a path earns by being sent on; the shorter, the sooner quiet paths are explored again.
+    pub memory: Duration,
+    /// Scales the confidence bound on the latency of each path; zero turns exploration off.
+    pub confidence: f64,
+    /// Share of the latency of the fastest path another path must optimistically undercut to be explored.
+    pub explore_margin: f64,
+    /// Explores with a small probe rather than a copy of the datagram.
+    pub probe: bool,
+    /// Floor of the latency of a path, keeping idle paths from dominating.
+    pub epsilon_latency: Duration,
+    /// How long the weights are kept before recomputing them from fresh stats.
+    pub rank_update_cool_down: Duration,
+    /// How strongly faster paths are preferred; low values all but always pick the fastest path, high values approach a uniform choice.
+    pub temperature: f64,
+}
+impl Default for WeightedConfig {
+    fn default() -> Self {
+        Self {
+            memory: Duration::from_millis(500),
+            confidence: 0.5,
+            explore_margin: 0.1,
+            probe: true,
+            epsilon_latency: Duration::from_millis(1),
+            rank_update_cool_down: Duration::from_secs(1),
+            temperature: 0.5,
+        }
+    }
+}
+
+/// How unsure the RTT estimate of a path is, from 0 for a steady path to 1 for an erratic or unmeasured one.
+fn uncertainty(stat: &Stat) -> f64 {
+    let Some(rtt) = stat.rtt() else {
+        return 1.;
+    };
+    if rtt.smoothed().is_zero() {
+        return 1.;
+    }
+    (rtt.var().as_secs_f64() / rtt.smoothed().as_secs_f64()).min(1.)
+}
+
+/// Writes a probability distribution over the paths to `out`.
+///
+/// Each usable path scores its latency relative to the fastest one, from 1 for the fastest towards 0 for the slowest, and the scores go through a softmax at `temperature`. Paths that are not usable get a weight of zero.
+fn rank(
+    stats: &[Stat],
+    usable: &[bool],
+    out: &mut Vec<f64>,
+    epsilon_latency: Duration,
+    temperature: f64,
+    now: Instant,
+) {
+    let latency = |stat: &Stat| stat.latency(now).max(epsilon_latency).as_secs_f64();
+    let min_latency = stats
+        .iter()
+        .zip(usable)
+        .filter(|(_, usable)| **usable)
+        .map(|(stat, _)| latency(stat))
+        .fold(f64::INFINITY, f64::min);
+    out.clear();
+    for (stat, &usable) in stats.iter().zip(usable) {
+        if !usable {
+            out.push(0.);
+            continue;
+        }
+        let score = min_latency / latency(stat);
+        // Shifted by the top score of 1 to keep `exp` from overflowing
+        out.push(((score - 1.) / temperature).exp());
+    }
+    let sum: f64 = out.iter().sum();
+    if 0. < sum {
+        out.iter_mut().for_each(|weight| *weight /= sum);
+    }
+}
+
+/// Cycles through the paths one datagram at a time.
+#[derive(Debug, Clone, Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl Scheduler for RoundRobin {
+    fn schedule(&mut self, stats: &[Stat], _now: Instant, out: &mut Plan) {
+        let next = usable_paths(stats)
+            .find(|&i| self.next <= i)
+            .or_else(|| usable_paths(stats).next());
+        let Some(i) = next else {
+            return;
+        };
+        self.next = i + 1;
+        out.send.push(i);
+    }
+}
+
+/// Always sends on the path with the lowest latency.
+#[derive(Debug, Clone, Default)]
+pub struct MinLatency {}
+impl MinLatency {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl Scheduler for MinLatency {
+    fn schedule(&mut self, stats: &[Stat], now: Instant, out: &mut Plan) {
+        let best = usable_paths(stats).min_by_key(|&i| stats[i].latency(now));
+        if let Some(i) = best {
+            out.send.push(i);
+        }
+    }
+}
+
+/// Picks paths at random in proportion to fixed weights.
+#[derive(Debug, Clone)]
+pub struct WeightedStatic {
+    weights: Vec<f64>,
+    rng: ChaCha8Rng,
+}
+impl WeightedStatic {
+    /// Path `i` gets `weights[i]`; paths beyond `weights` are never picked.
+    ///
+    /// Returns `None` if a weight is negative or not finite, or if all of them are zero.
+    pub fn new(weights: Vec<f64>) -> Option<Self> {
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.) {
             return None;
         }
-        let mut rng = rand::thread_rng();
-        let p = rng.gen_range(0. ..=1.);
-        if p < EXPLORE_PROB {
+        let sum: f64 = weights.iter().sum();
+        if sum <= 0. {
             return None;
         }
-        let next = rng.gen_range(0..self.values.len() - 1);
-        Some(if next < except { next } else { next + 1 })
+        Some(Self {
+            weights,
+            rng: ChaCha8Rng::from_entropy(),
+        })
+    }
+    /// Draws from `rng` instead of OS entropy; ChaCha8 is portable, so a seed replays the same choices on any platform.
+    pub fn with_rng(mut self, rng: ChaCha8Rng) -> Self {
+        self.rng = rng;
+        self
+    }
+}
+impl Scheduler for WeightedStatic {
+    fn schedule(&mut self, stats: &[Stat], _now: Instant, out: &mut Plan) {
+        let candidates = usable_paths(stats)
+            .filter(|&i| self.weights.get(i).is_some_and(|&w| 0. < w))
+            .collect::<Vec<_>>();
+        let sum: f64 = candidates.iter().map(|&i| self.weights[i]).sum();
+        let Some(&last) = candidates.last() else {
+            return;
+        };
+        let mut remaining = self.rng.gen_range(0. ..sum);
+        for &i in &candidates {
+            let weight = self.weights[i];
+            if remaining < weight {
+                out.send.push(i);
+                return;
+            }
+            remaining -= weight;
+        }
+        out.send.push(last);
+    }
+    fn weight(&self, path: usize) -> Option<f64> {
+        let sum: f64 = self.weights.iter().sum();
+        Some(self.weights.get(path).map_or(0., |w| w / sum))
     }
 }
 
-fn rank<'a>(stats: impl Iterator<Item = &'a Stat> + Clone, out: &mut Vec<f64>, now: Instant) {
-    let mut latency_sum = Duration::ZERO;
-    for stat in stats.clone() {
-        let latency = stat.latency(now).max(EPSILON_LATENCY);
-        latency_sum += latency;
+/// Sends every datagram on several paths at once, trading bandwidth for latency and loss.
+///
+/// The receiver delivers only the first copy to arrive.
+#[derive(Debug, Clone)]
+pub struct Redundant {
+    best: Option<NonZeroUsize>,
+    order: Vec<usize>,
+}
+impl Redundant {
+    /// Sends on every live path.
+    pub fn all() -> Self {
+        Self {
+            best: None,
+            order: vec![],
+        }
     }
-    out.clear();
-    for stat in stats {
-        let latency = stat.latency(now).max(EPSILON_LATENCY);
-        let weight = 1. - latency.as_secs_f64() / latency_sum.as_secs_f64();
-        out.push(weight);
+    /// Sends on the `k` live paths with the lowest latency.
+    pub fn best(k: NonZeroUsize) -> Self {
+        Self {
+            best: Some(k),
+            order: vec![],
+        }
+    }
+}
+impl Scheduler for Redundant {
+    fn schedule(&mut self, stats: &[Stat], now: Instant, out: &mut Plan) {
+        self.order.clear();
+        self.order.extend(usable_paths(stats));
+        self.order.sort_by_key(|&i| stats[i].latency(now));
+        let k = self
+            .best
+            .map_or(self.order.len(), |k| k.get().min(self.order.len()));
+        out.send.extend(&self.order[..k]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    /// Paths that answered probes after each of `rtts` steadily.
+    fn measured(rtts: &[u64], now: Instant) -> Vec<Stat> {
+        rtts.iter()
+            .map(|&rtt| {
+                let mut stat = Stat::new(PathRole::Primary, now);
+                for _ in 0..16 {
+                    stat.rtt_sample(Duration::from_millis(rtt));
+                }
+                stat
+            })
+            .collect()
+    }
+    /// The primary and the explored path of each of `n` datagrams sent 100 ms apart.
+    fn replay(
+        scheduler: &mut dyn Scheduler,
+        stats: &[Stat],
+        n: usize,
+    ) -> Vec<(usize, Option<usize>)> {
+        let clock = ManualClock::new(Instant::now());
+        let mut plan = Plan::default();
+        let mut choices = vec![];
+        for _ in 0..n {
+            plan.clear();
+            scheduler.schedule(stats, clock.now(), &mut plan);
+            choices.push((plan.send[0], plan.probe.first().copied()));
+            clock.advance(Duration::from_millis(100));
+        }
+        choices
+    }
+
+    #[test]
+    fn replay_weighted() {
+        let stats = measured(&[10, 20, 40], Instant::now());
+        let new = || Weighted::new(stats.len()).with_rng(ChaCha8Rng::seed_from_u64(7));
+        let choices = replay(&mut new(), &stats, 12);
+        assert_eq!(choices, replay(&mut new(), &stats, 12));
+        // The fastest path dominates; the others are explored first
+        let expected = [
+            (0, Some(1)),
+            (0, Some(2)),
+            (1, None),
+            (1, None),
+            (0, None),
+            (0, None),
+            (0, None),
+            (1, None),
+            (0, None),
+            (2, None),
+            (0, None),
+            (0, None),
+        ];
+        assert_eq!(choices, expected);
+    }
+
+    #[test]
+    fn replay_weighted_static() {
+        let stats = measured(&[10, 20, 40], Instant::now());
+        let new = || {
+            WeightedStatic::new(vec![1., 2., 3.])
+                .unwrap()
+                .with_rng(ChaCha8Rng::seed_from_u64(7))
+        };
+        let choices = replay(&mut new(), &stats, 12);
+        assert_eq!(choices, replay(&mut new(), &stats, 12));
+        let expected = [
+            (0, None),
+            (1, None),
+            (2, None),
+            (2, None),
+            (2, None),
+            (1, None),
+            (0, None),
+            (2, None),
+            (1, None),
+            (2, None),
+            (1, None),
+            (1, None),
+        ];
+        assert_eq!(choices, expected);
+    }
+
+    /// Softmax over the latency of the fastest path relative to each, worked out independently of [`rank`].
+    fn softmax(rtts: &[u64], temperature: f64) -> Vec<f64> {
+        let min = *rtts.iter().min().unwrap() as f64;
+        let exps = rtts
+            .iter()
+            .map(|&rtt| ((min / rtt as f64 - 1.) / temperature).exp())
+            .collect::<Vec<_>>();
+        let sum: f64 = exps.iter().sum();
+        exps.iter().map(|exp| exp / sum).collect()
+    }
+    fn ranked(rtts: &[u64], temperature: f64) -> Weighted {
+        let now = Instant::now();
+        let config = WeightedConfig {
+            temperature,
+            ..Default::default()
+        };
+        let mut weighted = Weighted::with_config(rtts.len(), config)
+            .unwrap()
+            .with_rng(ChaCha8Rng::seed_from_u64(13));
+        weighted.update_rank(&measured(rtts, now), now);
+        weighted
+    }
+
+    #[test]
+    fn exploit_frequencies_follow_weights() {
+        const DRAWS: usize = 100_000;
+        let cases: [&[u64]; 3] = [&[10, 30], &[10, 20, 40], &[5, 10, 15, 20, 80]];
+        for rtts in cases {
+            let mut weighted = ranked(rtts, 0.5);
+            let sum: f64 = weighted.values.iter().sum();
+            assert!((sum - 1.).abs() < 1e-9, "{rtts:?}: weights sum to {sum}");
+            for (value, expected) in weighted.values.iter().zip(softmax(rtts, 0.5)) {
+                assert!((value - expected).abs() < 1e-9, "{rtts:?}");
+            }
+            let mut counts = vec![0; rtts.len()];
+            for _ in 0..DRAWS {
+                counts[weighted.choose_exploit().unwrap()] += 1;
+            }
+            for (i, &count) in counts.iter().enumerate() {
+                let frequency = count as f64 / DRAWS as f64;
+                let weight = weighted.values[i];
+                assert!(
+                    (frequency - weight).abs() < 0.01,
+                    "{rtts:?}: path {i} picked {frequency} of the time for a weight of {weight}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn temperature_extremes() {
+        let rtts = [10, 20, 40];
+        let cold = ranked(&rtts, 1e-3);
+        assert!(1. - 1e-9 < cold.values[0]);
+        let hot = ranked(&rtts, 1e6);
+        for value in &hot.values {
+            assert!((value - 1. / 3.).abs() < 1e-6);
+        }
+        for temperature in [0., -1., f64::INFINITY, f64::NAN] {
+            let config = WeightedConfig {
+                temperature,
+                ..Default::default()
+            };
+            assert!(Weighted::with_config(rtts.len(), config).is_none());
+        }
+    }
+
+    /// Share of `n` datagrams sent `gap` apart that came with an exploring probe.
+    fn explore_rate(
+        weighted: &mut Weighted,
+        stats: &[Stat],
+        clock: &ManualClock,
+        n: usize,
+        gap: Duration,
+    ) -> f64 {
+        let mut plan = Plan::default();
+        let mut explored = 0;
+        for _ in 0..n {
+            plan.clear();
+            weighted.schedule(stats, clock.now(), &mut plan);
+            explored += plan.probe.len();
+            clock.advance(gap);
+        }
+        explored as f64 / n as f64
+    }
+
+    #[test]
+    fn explore_less_as_confidence_grows() {
+        let clock = ManualClock::new(Instant::now());
+        let stats = measured(&[10, 40], clock.now());
+        let mut weighted = Weighted::new(stats.len()).with_rng(ChaCha8Rng::seed_from_u64(3));
+        let gap = Duration::from_millis(1);
+        let warm_up = explore_rate(&mut weighted, &stats, &clock, 100, gap);
+        let settled = explore_rate(&mut weighted, &stats, &clock, 10_000, gap);
+        assert!(
+            settled < 0.01,
+            "settled paths explored {settled} of the time"
+        );
+        assert!(settled < warm_up);
+        // A path left alone long enough is explored again
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(explore_rate(&mut weighted, &stats, &clock, 1, gap), 1.);
+    }
+
+    #[test]
+    fn explore_erratic_paths_sooner() {
+        let now = Instant::now();
+        let steady = measured(&[10, 20], now);
+        let mut erratic = steady.clone();
+        for rtt in [10, 30, 10, 30] {
+            erratic[1].rtt_sample(Duration::from_millis(rtt));
+        }
+        for (stats, expected) in [(&steady, None), (&erratic, Some(1))] {
+            let mut weighted = Weighted::new(stats.len()).with_rng(ChaCha8Rng::seed_from_u64(3));
+            weighted.update_rank(stats, now);
+            weighted.pulls = vec![50., 1.];
+            assert_eq!(weighted.choose_explore(0, stats, now), expected);
+        }
     }
 }
@@ -0,0 +1,182 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use primitive::sync::mutex::SpinMutex;
+
+use crate::{
+    clock::Clock,
+    schedule::{PathRole, PathState, Rtt, Stat, Stats},
+    write::UdpSender,
+};
+
+/// What a session has been doing, as seen from one end.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    /// Indexed by path.
+    pub paths: Vec<PathStats>,
+    pub total: Totals,
+    /// RTT samples of all paths; only kept if enabled in the session config.
+    pub latency: Option<LatencyHistogram>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PathStats {
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    pub traffic: Totals,
+    pub srtt: Option<Duration>,
+    /// Share of probes that went unanswered for about an RTO; `None` until the first probe is answered or times out.
+    pub loss: Option<f64>,
+    /// Probability of the scheduler picking the path, if its policy works that way.
+    pub weight: Option<f64>,
+    pub state: PathState,
+    pub role: PathRole,
+}
+
+/// Traffic counters, control frames included.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Totals {
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+    pub recv_packets: u64,
+    pub recv_bytes: u64,
+    /// Datagrams dropped because a copy of them was already delivered.
+    pub duplicates: u64,
+    /// Datagrams dropped because they could not be decoded.
+    pub bad_packets: u64,
+}
+impl Totals {
+    fn add(&mut self, other: &Self) {
+        self.sent_packets += other.sent_packets;
+        self.sent_bytes += other.sent_bytes;
+        self.recv_packets += other.recv_packets;
+        self.recv_bytes += other.recv_bytes;
+        self.duplicates += other.duplicates;
+        self.bad_packets += other.bad_packets;
+    }
+}
+
+const HISTOGRAM_BUCKETS: usize = 32;
+/// Counts of RTT samples in power-of-two buckets of microseconds.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+}
+impl LatencyHistogram {
+    pub(crate) fn record(&mut self, sample: Duration) {
+        let micros = u64::try_from(sample.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+    /// Pairs of the exclusive upper bound of each bucket and the samples in it.
+    ///
+    /// The last bucket also holds every sample beyond its bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| (Duration::from_micros(1 << i), count))
+    }
+}
+
+/// How long a probe may go unanswered before it counts as lost, until the path has an RTT estimate.
+const INITIAL_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Floor of the RTT variance allowed for, so that jitter of fast paths is not taken for loss.
+const PROBE_TIMEOUT_GRANULARITY: Duration = Duration::from_millis(1);
+/// About one RTO of the path.
+fn probe_timeout(rtt: Option<&Rtt>) -> Duration {
+    rtt.map_or(INITIAL_PROBE_TIMEOUT, |rtt| {
+        rtt.smoothed() + (rtt.var() * 4).max(PROBE_TIMEOUT_GRANULARITY)
+    })
+}
+
+/// The shared state [`SessionStats`] are read from, outliving neither half of the session.
+///
+/// Paths are only ever appended, so the index of a path never changes.
+#[derive(Debug)]
+pub(crate) struct StatsSource {
+    paths: SpinMutex<(Stats, Vec<Arc<UdpSender>>)>,
+    histogram: Option<SpinMutex<LatencyHistogram>>,
+    clock: Arc<dyn Clock>,
+}
+impl StatsSource {
+    pub fn new(
+        stats: Stats,
+        senders: Vec<Arc<UdpSender>>,
+        latency_histogram: bool,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<Self> {
+        assert_eq!(stats.len(), senders.len());
+        let histogram = latency_histogram.then(|| SpinMutex::new(LatencyHistogram::default()));
+        Arc::new(Self {
+            paths: SpinMutex::new((stats, senders)),
+            histogram,
+            clock,
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.paths.lock().0.len()
+    }
+    /// The stats and senders of every path so far.
+    pub fn paths(&self) -> (Stats, Vec<Arc<UdpSender>>) {
+        self.paths.lock().clone()
+    }
+    pub fn stat(&self, path: usize) -> Arc<SpinMutex<Stat>> {
+        Arc::clone(&self.paths.lock().0[path])
+    }
+    pub fn sender(&self, path: usize) -> Arc<UdpSender> {
+        Arc::clone(&self.paths.lock().1[path])
+    }
+    /// Returns the index of the new path.
+    pub fn add_path(&self, stat: Stat, sender: Arc<UdpSender>) -> usize {
+        let mut paths = self.paths.lock();
+        paths.0.push(Arc::new(SpinMutex::new(stat)));
+        paths.1.push(sender);
+        paths.0.len() - 1
+    }
+    pub fn record_rtt(&self, sample: Duration) {
+        if let Some(histogram) = &self.histogram {
+            histogram.lock().record(sample);
+        }
+    }
+    pub fn snapshot(&self) -> SessionStats {
+        let mut total = Totals::default();
+        let (stats, senders) = self.paths();
+        let now = self.clock.now();
+        let paths = stats
+            .iter()
+            .zip(&senders)
+            .map(|(stat, sender)| {
+                let stat = *stat.lock();
+                let (sent_packets, sent_bytes) = sender.counters();
+                let traffic = Totals {
+                    sent_packets,
+                    sent_bytes,
+                    recv_packets: stat.recv_packets(),
+                    recv_bytes: stat.recv_bytes(),
+                    duplicates: stat.duplicates(),
+                    bad_packets: stat.bad_packets(),
+                };
+                total.add(&traffic);
+                let loss = sender.ping_loss(now, probe_timeout(stat.rtt()));
+                PathStats {
+                    local_addr: sender.local_addr(),
+                    peer_addr: sender.peer_addr(),
+                    traffic,
+                    srtt: stat.rtt().map(|rtt| rtt.smoothed()),
+                    loss,
+                    weight: stat.weight(),
+                    state: stat.state(),
+                    role: stat.role(),
+                }
+            })
+            .collect();
+        SessionStats {
+            paths,
+            total,
+            latency: self.histogram.as_ref().map(|h| h.lock().clone()),
+        }
+    }
+}
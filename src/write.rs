@@ -1,83 +1,507 @@
 use std::{
+    collections::VecDeque,
     io,
-    sync::Arc,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
     time::{Duration, Instant},
 };
 
-use tokio::net::UdpSocket;
+use primitive::sync::mutex::SpinMutex;
+use tokio::{net::UdpSocket, sync::Notify};
 use udp_listener::{ConnWrite, PACKET_BUFFER_LENGTH};
 
 use crate::{
-    message::{Header, Init},
-    schedule::{Rank, Stats},
+    clock::Clock,
+    message::{Features, Kind, Side, TokenBuf, encode_close, encode_timestamp, stamp_path},
+    qlog::EventLog,
+    schedule::{PathState, Plan, Scheduler, Stat, Stats},
+    stats::{SessionStats, StatsSource},
 };
 
-const RANK_UPDATE_COOL_DOWN: Duration = Duration::from_secs(1);
-
 #[derive(Debug)]
 pub struct MpUdpWrite {
     stats: Stats,
-    rank: Rank,
-    last_rank_update: Instant,
-    conns: Vec<UdpSender>,
-    init: Option<Init>,
+    scheduler: Box<dyn Scheduler>,
+    snapshot: Vec<Stat>,
+    plan: Plan,
+    conns: Vec<Arc<UdpSender>>,
+    side: Side,
+    features: Features,
+    next_seq: u64,
     buf: Vec<u8>,
+    clock: Arc<dyn Clock>,
+    send_stats: SendStats,
+    source: Arc<StatsSource>,
+    log: EventLog,
+    shut_down: bool,
 }
 impl MpUdpWrite {
-    pub(crate) fn new(conns: Vec<UdpSender>, stats: Stats, init: Option<Init>) -> Self {
-        assert_eq!(conns.len(), stats.len());
-        let now = Instant::now();
-        let rank = Rank::new(conns.len());
+    pub(crate) fn new(
+        source: Arc<StatsSource>,
+        side: Side,
+        features: Features,
+        scheduler: Box<dyn Scheduler>,
+        clock: Arc<dyn Clock>,
+        log: EventLog,
+    ) -> Self {
         let buf = Vec::with_capacity(PACKET_BUFFER_LENGTH);
+        let (stats, conns) = source.paths();
         Self {
             conns,
             stats,
-            rank,
-            last_rank_update: now,
-            init,
+            scheduler,
+            snapshot: vec![],
+            plan: Plan::default(),
+            side,
+            features,
+            next_seq: 0,
             buf,
+            clock,
+            send_stats: SendStats::default(),
+            source,
+            log,
+            shut_down: false,
         }
     }
     pub async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let now = Instant::now();
-        if RANK_UPDATE_COOL_DOWN < now.duration_since(self.last_rank_update) {
-            let stats = self.stats.iter().map(|s| *s.lock()).collect::<Vec<_>>();
-            self.rank.update_rank(stats.iter(), now);
-            self.last_rank_update = now;
-        }
-        let buf = match &self.init {
-            Some(init) => {
-                self.buf.clear();
-                let with_payload = true;
-                let header = Header::new(*init, with_payload);
-                self.buf.extend(header.encode());
-                self.buf.extend(buf);
-                &self.buf
+        if self.shut_down {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "session shut down",
+            ));
+        }
+        self.refresh_paths();
+        let now = self.clock.now();
+        self.snapshot.clear();
+        self.snapshot.extend(self.stats.iter().map(|s| *s.lock()));
+        self.plan.clear();
+        self.scheduler.schedule(&self.snapshot, now, &mut self.plan);
+        self.publish_weights();
+        // Peers that do not answer probes can only be measured with real traffic
+        if !self.features.contains(Features::PING) {
+            self.plan.send.append(&mut self.plan.probe);
+        }
+        if self.plan.send.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "scheduler chose no path",
+            ));
+        }
+        let payload_len = buf.len();
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.log.emit_extra("datagram_scheduled", |f| {
+            f.num("seq", seq)
+                .num("exploit", self.plan.send[0])
+                .nums("copies", &self.plan.send[1..])
+                .nums("probes", &self.plan.probe);
+        });
+        self.side.encode_data(seq, buf, &mut self.buf);
+        let buf = &mut self.buf;
+        // The datagram is out as long as one of its copies made it onto the wire
+        let mut sent = 0;
+        let mut last_err = None;
+        for &i in &self.plan.send {
+            self.stats[i].lock().sent(now);
+            match self.conns[i].send(buf).await {
+                Ok(_) => sent += 1,
+                Err(e) => last_err = Some(e),
+            }
+        }
+        if 0 < sent {
+            self.send_stats.datagrams += 1;
+            self.send_stats.copies += sent - 1;
+            self.send_stats.copy_bytes += (sent - 1) * buf.len() as u64;
+        }
+        if !self.plan.probe.is_empty() {
+            self.side
+                .encode_frame(Kind::Ping, &encode_timestamp(now), &mut self.buf);
+            for &i in &self.plan.probe {
+                if self.conns[i].send_ping(&mut self.buf, now).await.is_ok() {
+                    self.send_stats.probes += 1;
+                    self.send_stats.probe_bytes += self.buf.len() as u64;
+                }
+            }
+        }
+        match last_err {
+            Some(e) if sent == 0 => Err(e),
+            _ => Ok(payload_len),
+        }
+    }
+    /// Probes every path once and waits up to `timeout` for the answers.
+    pub(crate) async fn ping(&mut self, pong: &Notify, timeout: Duration) -> Vec<Option<Duration>> {
+        self.refresh_paths();
+        let samples = self
+            .stats
+            .iter()
+            .map(|s| s.lock().rtt_samples())
+            .collect::<Vec<_>>();
+        let now = self.clock.now();
+        self.side
+            .encode_frame(Kind::Ping, &encode_timestamp(now), &mut self.buf);
+        for conn in &self.conns {
+            let _ = conn.send_ping(&mut self.buf, now).await;
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let notified = pong.notified();
+            let rtts = self
+                .stats
+                .iter()
+                .zip(&samples)
+                .map(|(s, &before)| {
+                    let s = s.lock();
+                    if s.rtt_samples() == before {
+                        return None;
+                    }
+                    s.rtt().map(|rtt| rtt.latest())
+                })
+                .collect::<Vec<_>>();
+            if rtts.iter().all(|rtt| rtt.is_some()) {
+                return rtts;
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return rtts;
+            }
+        }
+    }
+    /// Tells the peer the session is over by sending a [`Kind::Close`] frame on every path; later sends fail.
+    ///
+    /// The frame tells the peer how many datagrams to wait for before it stops receiving.
+    /// It is not retransmitted, so a peer that misses it on every path only finds out once its paths die.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        if !self.features.contains(Features::CLOSE) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "peer does not understand close frames",
+            ));
+        }
+        self.shut_down = true;
+        self.log.emit("connection_closed", |f| {
+            f.str("owner", "local");
+        });
+        let body = encode_close(self.side.secret(), self.next_seq);
+        self.side.encode_frame(Kind::Close, &body, &mut self.buf);
+        let mut sent = false;
+        let mut last_err = None;
+        for conn in &self.conns {
+            match conn.send(&mut self.buf).await {
+                Ok(_) => sent = true,
+                Err(e) => last_err = Some(e),
             }
-            None => buf,
+        }
+        match last_err {
+            Some(e) if !sent => Err(e),
+            _ => Ok(()),
+        }
+    }
+    /// Stops scheduling `path` and asks the peer to do the same by sending a [`Kind::Retire`] frame on it.
+    ///
+    /// The frame is resent until the peer echoes it or the path would count as dead.
+    ///
+    /// Returns the new state if the path was not already retired.
+    pub(crate) async fn retire_path(&mut self, path: usize) -> io::Result<Option<PathState>> {
+        self.refresh_paths();
+        let Some(conn) = self.conns.get(path) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no path {path}"),
+            ));
         };
-        let exploit = self.rank.choose_exploit().unwrap();
-        let explore = self.rank.choose_explore(exploit);
-        if let Some(explore) = explore {
-            self.stats[explore].lock().sent(now);
-            self.conns[explore].send(buf).await?;
-        }
-        let n = self.conns[exploit].send(buf).await?;
-        self.stats[exploit].lock().sent(now);
-        Ok(n)
+        self.side
+            .encode_frame(Kind::Retire, &self.side.secret().encode(), &mut self.buf);
+        conn.send(&mut self.buf).await?;
+        conn.retire();
+        Ok(self.stats[path].lock().retire())
+    }
+    /// Picks up the paths that joined since the last call.
+    fn refresh_paths(&mut self) {
+        if self.source.len() == self.stats.len() {
+            return;
+        }
+        (self.stats, self.conns) = self.source.paths();
+    }
+    /// Per-path counters and estimates of the session.
+    pub fn stats(&self) -> SessionStats {
+        self.source.snapshot()
+    }
+    /// Counters of the traffic put on the wire, including the extra copies the scheduler asked for.
+    pub fn send_stats(&self) -> SendStats {
+        self.send_stats
+    }
+    /// Hands the weights the scheduler settled on to the stats of each path.
+    fn publish_weights(&self) {
+        let mut changed = false;
+        for (i, stat) in self.stats.iter().enumerate() {
+            let weight = self.scheduler.weight(i);
+            let mut stat = stat.lock();
+            changed |= stat.weight() != weight;
+            stat.set_weight(weight);
+        }
+        if !changed {
+            return;
+        }
+        self.log.emit("path_weights_updated", |f| {
+            let weights = (0..self.stats.len()).map(|i| {
+                let weight = self.scheduler.weight(i);
+                weight.map_or_else(|| String::from("null"), |w| w.to_string())
+            });
+            f.nums("weights", weights);
+        });
+    }
+    /// Replaces the policy deciding which paths carry each datagram, e.g. with [`crate::schedule::Redundant`] for latency-critical traffic.
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn Scheduler>) {
+        self.scheduler = scheduler;
     }
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendStats {
+    pub datagrams: u64,
+    /// Datagrams sent on more than one path count once per extra path.
+    pub copies: u64,
+    /// Bytes put on the wire by the extra copies, headers included.
+    pub copy_bytes: u64,
+    /// Probes sent in place of copies to measure paths.
+    pub probes: u64,
+    pub probe_bytes: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct UdpSender {
+    route: SpinMutex<Route>,
+    /// Index of the path at the client, which the listener tells paths apart by.
+    path_id: u32,
+    sent_packets: AtomicU64,
+    sent_bytes: AtomicU64,
+    pings: SpinMutex<Pings>,
+    /// Wakes the receive task of the path once it is retired here.
+    retiring: Notify,
+}
+/// Most pings of a path awaiting an answer; older ones count as lost.
+const MAX_PINGS_IN_FLIGHT: usize = 64;
+/// The pings of a path, by when they were sent.
+#[derive(Debug, Default)]
+struct Pings {
+    sent: u64,
+    lost: u64,
+    in_flight: VecDeque<Instant>,
+}
+/// Where the datagrams of a path go; replaced when the path migrates.
+#[derive(Debug, Clone)]
+struct Route {
+    conn: Arc<UdpSenderConn>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    /// `None` once the peer has proven it is at `peer_addr`.
+    unvalidated: Option<Arc<Unvalidated>>,
+}
+/// Most bytes sent to a peer address for every byte received from it until it is validated.
+const AMPLIFICATION_FACTOR: u64 = 3;
+/// Traffic exchanged with a peer address that has not answered a [`Kind::Challenge`] yet.
 #[derive(Debug)]
-pub(crate) enum UdpSender {
+struct Unvalidated {
+    token: TokenBuf,
+    recv_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+impl UdpSender {
+    /// The address of the client is only trusted once it answers [`Self::challenge`] or the path is [`Self::trust`]ed.
+    pub fn from_server(
+        conn_write: ConnWrite<UdpSocket>,
+        local_addr: SocketAddr,
+        path_id: u32,
+    ) -> Self {
+        let peer_addr = conn_write.peer_addr();
+        let unvalidated = Unvalidated {
+            token: rand::random::<u64>().to_be_bytes(),
+            recv_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+        };
+        let route = Route {
+            conn: Arc::new(UdpSenderConn::Server(conn_write)),
+            local_addr,
+            peer_addr,
+            unvalidated: Some(Arc::new(unvalidated)),
+        };
+        Self::new(route, path_id)
+    }
+    pub fn from_client(socket: Arc<UdpSocket>, path_id: u32) -> io::Result<Self> {
+        let route = Route {
+            local_addr: socket.local_addr()?,
+            peer_addr: socket.peer_addr()?,
+            conn: Arc::new(UdpSenderConn::Client(socket)),
+            unvalidated: None,
+        };
+        Ok(Self::new(route, path_id))
+    }
+    fn new(route: Route, path_id: u32) -> Self {
+        Self {
+            route: SpinMutex::new(route),
+            path_id,
+            sent_packets: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            pings: SpinMutex::new(Pings::default()),
+            retiring: Notify::new(),
+        }
+    }
+    /// Client frames get the path stamped into their header first.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] if an unvalidated peer address has used up what it may be sent.
+    pub async fn send(&self, frame: &mut [u8]) -> io::Result<usize> {
+        let (conn, unvalidated) = {
+            let route = self.route.lock();
+            (Arc::clone(&route.conn), route.unvalidated.clone())
+        };
+        if let Some(unvalidated) = &unvalidated {
+            let len = frame.len() as u64;
+            let budget = unvalidated.recv_bytes.load(Ordering::Relaxed) * AMPLIFICATION_FACTOR;
+            let sent = unvalidated.sent_bytes.fetch_add(len, Ordering::Relaxed) + len;
+            if budget < sent {
+                unvalidated.sent_bytes.fetch_sub(len, Ordering::Relaxed);
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "amplification limit of unvalidated peer address reached",
+                ));
+            }
+        }
+        let n = match &*conn {
+            UdpSenderConn::Server(conn_write) => conn_write.send(frame).await,
+            UdpSenderConn::Client(socket) => {
+                stamp_path(frame, self.path_id);
+                socket.send(frame).await
+            }
+        }?;
+        self.sent_packets.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+    /// Sends a [`Kind::Ping`] frame stamped with `sent`, counting it towards the loss estimate of the path.
+    pub async fn send_ping(&self, frame: &mut [u8], sent: Instant) -> io::Result<usize> {
+        let n = self.send(frame).await?;
+        let mut pings = self.pings.lock();
+        pings.sent += 1;
+        pings.in_flight.push_back(sent);
+        if MAX_PINGS_IN_FLIGHT < pings.in_flight.len() {
+            pings.in_flight.pop_front();
+            pings.lost += 1;
+        }
+        Ok(n)
+    }
+    /// Takes the ping stamped with `sent` off the ones in flight, unless it counts as lost already.
+    pub fn answered(&self, sent: Instant) {
+        let mut pings = self.pings.lock();
+        if let Some(i) = pings.in_flight.iter().position(|&ping| ping == sent) {
+            pings.in_flight.remove(i);
+        }
+    }
+    /// Share of the pings sent before `timeout` ago that went unanswered; `None` until one of them was.
+    ///
+    /// Pings answered after they timed out still count as lost.
+    pub fn ping_loss(&self, now: Instant, timeout: Duration) -> Option<f64> {
+        let mut pings = self.pings.lock();
+        while let Some(&sent) = pings.in_flight.front() {
+            if now.saturating_duration_since(sent) < timeout {
+                break;
+            }
+            pings.in_flight.pop_front();
+            pings.lost += 1;
+        }
+        let settled = pings.sent - pings.in_flight.len() as u64;
+        (0 < settled).then(|| pings.lost as f64 / settled as f64)
+    }
+    /// Hands retransmitting [`Kind::Retire`] over to the receive task of the path, which waits for the echo.
+    pub fn retire(&self) {
+        self.retiring.notify_one();
+    }
+    /// Completes once the path is retired here.
+    pub async fn retiring(&self) {
+        self.retiring.notified().await;
+    }
+    /// Counts bytes received from the peer towards what an unvalidated address may be sent.
+    pub fn received(&self, bytes: usize) {
+        if let Some(unvalidated) = &self.route.lock().unvalidated {
+            unvalidated
+                .recv_bytes
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+    /// The token the peer has to echo in a [`Kind::Response`], unless its address is validated already.
+    pub fn challenge(&self) -> Option<TokenBuf> {
+        self.route.lock().unvalidated.as_ref().map(|u| u.token)
+    }
+    /// Lifts the limits on the peer address if `token` answers the challenge; returns whether it did.
+    pub fn validate(&self, token: &[u8]) -> bool {
+        let mut route = self.route.lock();
+        if route.unvalidated.as_ref().is_none_or(|u| u.token != token) {
+            return false;
+        }
+        route.unvalidated = None;
+        true
+    }
+    /// Lifts the limits on the peer address without a challenge, e.g. for peers that cannot answer one.
+    pub fn trust(&self) {
+        self.route.lock().unvalidated = None;
+    }
+    /// Sends everything from now on where `other` would, keeping the counters of the path.
+    pub fn migrate(&self, other: &UdpSender) {
+        let route = other.route.lock().clone();
+        *self.route.lock() = route;
+    }
+    pub fn path_id(&self) -> u32 {
+        self.path_id
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.route.lock().local_addr
+    }
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.route.lock().peer_addr
+    }
+    /// Packets and bytes sent so far.
+    pub fn counters(&self) -> (u64, u64) {
+        (
+            self.sent_packets.load(Ordering::Relaxed),
+            self.sent_bytes.load(Ordering::Relaxed),
+        )
+    }
+}
+#[derive(Debug)]
+enum UdpSenderConn {
     Server(ConnWrite<UdpSocket>),
     Client(Arc<UdpSocket>),
 }
-impl UdpSender {
-    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
-        match &self {
-            UdpSender::Server(conn_write) => conn_write.send(buf).await,
-            UdpSender::Client(socket) => socket.send(buf).await,
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::HEADER_SIZE;
+
+    #[tokio::test]
+    async fn ping_loss_waits_for_timeout() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(socket.local_addr().unwrap()).await.unwrap();
+        let sender = UdpSender::from_client(Arc::new(socket), 0).unwrap();
+        let timeout = Duration::from_millis(100);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut frame = vec![0; HEADER_SIZE];
+        assert_eq!(sender.ping_loss(start, timeout), None);
+        for ms in [0, 10, 20, 150] {
+            sender.send_ping(&mut frame, at(ms)).await.unwrap();
         }
+        // Nothing has timed out yet, however long the answers take
+        assert_eq!(sender.ping_loss(at(50), timeout), None);
+        sender.answered(at(10));
+        assert_eq!(sender.ping_loss(at(50), timeout), Some(0.));
+        // The pings at 0 and 20 ms time out, the one at 150 ms is still in flight
+        assert_eq!(sender.ping_loss(at(200), timeout), Some(2. / 3.));
+        // Late answers do not count
+        sender.answered(at(20));
+        assert_eq!(sender.ping_loss(at(200), timeout), Some(2. / 3.));
+        sender.answered(at(150));
+        assert_eq!(sender.ping_loss(at(200), timeout), Some(0.5));
     }
 }